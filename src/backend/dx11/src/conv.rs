@@ -1,16 +1,17 @@
-use hal::format::{Format};
+use hal::format::{Format, SurfaceType};
 use hal::pso::{
     BlendDesc, BlendOp, BlendState, ColorBlendDesc, Comparison, DepthBias, DepthStencilDesc,
     DepthTest, Face, Factor, PolygonMode, Rasterizer, Rect, StencilFace, StencilOp, StencilTest,
     Viewport, Stage, State, StencilValue, FrontFace,
 };
 use hal::image::{Anisotropic, Filter, WrapMode};
-use hal::{IndexType, Primitive};
+use hal::{IndexType, PresentMode, Primitive};
 
 use spirv_cross::spirv;
 
+use winapi::shared::dxgi::{DXGI_SWAP_EFFECT, DXGI_SWAP_EFFECT_DISCARD, DXGI_SWAP_EFFECT_FLIP_DISCARD};
 use winapi::shared::dxgiformat::*;
-use winapi::shared::minwindef::{FALSE, INT, TRUE};
+use winapi::shared::minwindef::{FALSE, INT, TRUE, UINT};
 
 use winapi::um::d3dcommon::*;
 use winapi::um::d3d11::*;
@@ -28,6 +29,7 @@ pub fn map_index_type(ty: IndexType) -> DXGI_FORMAT {
 pub fn viewable_format(format: DXGI_FORMAT) -> DXGI_FORMAT {
     match format {
         DXGI_FORMAT_D32_FLOAT_S8X24_UINT => DXGI_FORMAT_R32_FLOAT_X8X24_TYPELESS,
+        DXGI_FORMAT_D24_UNORM_S8_UINT => DXGI_FORMAT_R24_UNORM_X8_TYPELESS,
         DXGI_FORMAT_D32_FLOAT => DXGI_FORMAT_R32_FLOAT,
         DXGI_FORMAT_D16_UNORM => DXGI_FORMAT_R16_UNORM,
         _ => format
@@ -38,9 +40,16 @@ pub fn viewable_format(format: DXGI_FORMAT) -> DXGI_FORMAT {
 pub fn map_format(format: Format) -> Option<DXGI_FORMAT> {
     use hal::format::Format::*;
 
+    // Handling packed formats according to the platform endianness.
+    // Note: on little-endian targets B4G4R4A4 has the A4R4G4B4 layout, which HAL doesn't
+    // have, so the 4-4-4-4 format stays unmapped there. The 5-5-5-1 formats require DXGI 1.2.
+    let reverse = unsafe { 1 == *(&1u32 as *const _ as *const u8) };
     let format = match format {
-        R5g6b5Unorm => DXGI_FORMAT_B5G6R5_UNORM,
-        R5g5b5a1Unorm => DXGI_FORMAT_B5G5R5A1_UNORM,
+        Bgra4Unorm    if !reverse => DXGI_FORMAT_B4G4R4A4_UNORM,
+        R5g6b5Unorm    if reverse => DXGI_FORMAT_B5G6R5_UNORM,
+        B5g6r5Unorm   if !reverse => DXGI_FORMAT_B5G6R5_UNORM,
+        B5g5r5a1Unorm if !reverse => DXGI_FORMAT_B5G5R5A1_UNORM,
+        A1r5g5b5Unorm if reverse  => DXGI_FORMAT_B5G5R5A1_UNORM,
         R8Unorm => DXGI_FORMAT_R8_UNORM,
         R8Inorm => DXGI_FORMAT_R8_SNORM,
         R8Uint => DXGI_FORMAT_R8_UINT,
@@ -88,6 +97,7 @@ pub fn map_format(format: Format) -> Option<DXGI_FORMAT> {
         B10g11r11Ufloat => DXGI_FORMAT_R11G11B10_FLOAT,
         E5b9g9r9Ufloat => DXGI_FORMAT_R9G9B9E5_SHAREDEXP,
         D16Unorm => DXGI_FORMAT_D16_UNORM,
+        D24UnormS8Uint => DXGI_FORMAT_D24_UNORM_S8_UINT,
         D32Float => DXGI_FORMAT_D32_FLOAT,
         D32FloatS8Uint => DXGI_FORMAT_D32_FLOAT_S8X24_UINT,
         Bc1RgbUnorm => DXGI_FORMAT_BC1_UNORM,
@@ -111,6 +121,116 @@ pub fn map_format(format: Format) -> Option<DXGI_FORMAT> {
     Some(format)
 }
 
+/// Returns the format of the swapchain buffers for the given surface format.
+///
+/// Swap chains, flip model ones in particular, don't accept sRGB buffer formats.
+/// The buffers use the linear equivalent and sRGB is applied by the render target view.
+pub fn map_backbuffer_format(format: Format) -> Option<DXGI_FORMAT> {
+    match format {
+        Format::Bgra8Srgb => Some(DXGI_FORMAT_B8G8R8A8_UNORM),
+        Format::Rgba8Srgb => Some(DXGI_FORMAT_R8G8B8A8_UNORM),
+        Format::Bgra8Unorm |
+        Format::Rgba8Unorm |
+        Format::A2b10g10r10Unorm |
+        Format::Rgba16Float => map_format(format),
+        _ => None,
+    }
+}
+
+/// Returns `true` for three-channel formats that DXGI can't represent.
+///
+/// Data in these formats has to be padded to the four-channel equivalent
+/// (e.g. `Rgb8Unorm` to `Rgba8Unorm`) before it is uploaded.
+pub fn needs_rgb_expansion(format: Format) -> bool {
+    match format.base_format().0 {
+        SurfaceType::R8_G8_B8 |
+        SurfaceType::B8_G8_R8 |
+        SurfaceType::R16_G16_B16 => map_format(format).is_none(),
+        _ => false,
+    }
+}
+
+pub fn reverse_map_format(format: DXGI_FORMAT) -> Option<Format> {
+    use hal::format::Format::*;
+
+    let reverse = unsafe { 1 == *(&1u32 as *const _ as *const u8) };
+    let format = match format {
+        DXGI_FORMAT_B4G4R4A4_UNORM if !reverse => Bgra4Unorm,
+        DXGI_FORMAT_B5G6R5_UNORM if reverse => R5g6b5Unorm,
+        DXGI_FORMAT_B5G6R5_UNORM if !reverse => B5g6r5Unorm,
+        DXGI_FORMAT_B5G5R5A1_UNORM if !reverse => B5g5r5a1Unorm,
+        DXGI_FORMAT_B5G5R5A1_UNORM if reverse => A1r5g5b5Unorm,
+        DXGI_FORMAT_R8_UNORM => R8Unorm,
+        DXGI_FORMAT_R8_SNORM => R8Inorm,
+        DXGI_FORMAT_R8_UINT => R8Uint,
+        DXGI_FORMAT_R8_SINT => R8Int,
+        DXGI_FORMAT_R8G8_UNORM => Rg8Unorm,
+        DXGI_FORMAT_R8G8_SNORM => Rg8Inorm,
+        DXGI_FORMAT_R8G8_UINT => Rg8Uint,
+        DXGI_FORMAT_R8G8_SINT => Rg8Int,
+        DXGI_FORMAT_R8G8B8A8_UNORM => Rgba8Unorm,
+        DXGI_FORMAT_R8G8B8A8_SNORM => Rgba8Inorm,
+        DXGI_FORMAT_R8G8B8A8_UINT => Rgba8Uint,
+        DXGI_FORMAT_R8G8B8A8_SINT => Rgba8Int,
+        DXGI_FORMAT_R8G8B8A8_UNORM_SRGB => Rgba8Srgb,
+        DXGI_FORMAT_B8G8R8A8_UNORM => Bgra8Unorm,
+        DXGI_FORMAT_B8G8R8A8_UNORM_SRGB => Bgra8Srgb,
+        DXGI_FORMAT_R10G10B10A2_UNORM => A2b10g10r10Unorm,
+        DXGI_FORMAT_R10G10B10A2_UINT => A2b10g10r10Uint,
+        DXGI_FORMAT_R16_UNORM => R16Unorm,
+        DXGI_FORMAT_R16_SNORM => R16Inorm,
+        DXGI_FORMAT_R16_UINT => R16Uint,
+        DXGI_FORMAT_R16_SINT => R16Int,
+        DXGI_FORMAT_R16_FLOAT => R16Float,
+        DXGI_FORMAT_R16G16_UNORM => Rg16Unorm,
+        DXGI_FORMAT_R16G16_SNORM => Rg16Inorm,
+        DXGI_FORMAT_R16G16_UINT => Rg16Uint,
+        DXGI_FORMAT_R16G16_SINT => Rg16Int,
+        DXGI_FORMAT_R16G16_FLOAT => Rg16Float,
+        DXGI_FORMAT_R16G16B16A16_UNORM => Rgba16Unorm,
+        DXGI_FORMAT_R16G16B16A16_SNORM => Rgba16Inorm,
+        DXGI_FORMAT_R16G16B16A16_UINT => Rgba16Uint,
+        DXGI_FORMAT_R16G16B16A16_SINT => Rgba16Int,
+        DXGI_FORMAT_R16G16B16A16_FLOAT => Rgba16Float,
+        DXGI_FORMAT_R32_UINT => R32Uint,
+        DXGI_FORMAT_R32_SINT => R32Int,
+        DXGI_FORMAT_R32_FLOAT => R32Float,
+        DXGI_FORMAT_R32G32_UINT => Rg32Uint,
+        DXGI_FORMAT_R32G32_SINT => Rg32Int,
+        DXGI_FORMAT_R32G32_FLOAT => Rg32Float,
+        DXGI_FORMAT_R32G32B32_UINT => Rgb32Uint,
+        DXGI_FORMAT_R32G32B32_SINT => Rgb32Int,
+        DXGI_FORMAT_R32G32B32_FLOAT => Rgb32Float,
+        DXGI_FORMAT_R32G32B32A32_UINT => Rgba32Uint,
+        DXGI_FORMAT_R32G32B32A32_SINT => Rgba32Int,
+        DXGI_FORMAT_R32G32B32A32_FLOAT => Rgba32Float,
+        DXGI_FORMAT_R11G11B10_FLOAT => B10g11r11Ufloat,
+        DXGI_FORMAT_R9G9B9E5_SHAREDEXP => E5b9g9r9Ufloat,
+        DXGI_FORMAT_D16_UNORM => D16Unorm,
+        DXGI_FORMAT_D24_UNORM_S8_UINT => D24UnormS8Uint,
+        DXGI_FORMAT_D32_FLOAT => D32Float,
+        DXGI_FORMAT_D32_FLOAT_S8X24_UINT => D32FloatS8Uint,
+        DXGI_FORMAT_BC1_UNORM => Bc1RgbUnorm,
+        DXGI_FORMAT_BC1_UNORM_SRGB => Bc1RgbSrgb,
+        DXGI_FORMAT_BC2_UNORM => Bc2Unorm,
+        DXGI_FORMAT_BC2_UNORM_SRGB => Bc2Srgb,
+        DXGI_FORMAT_BC3_UNORM => Bc3Unorm,
+        DXGI_FORMAT_BC3_UNORM_SRGB => Bc3Srgb,
+        DXGI_FORMAT_BC4_UNORM => Bc4Unorm,
+        DXGI_FORMAT_BC4_SNORM => Bc4Inorm,
+        DXGI_FORMAT_BC5_UNORM => Bc5Unorm,
+        DXGI_FORMAT_BC5_SNORM => Bc5Inorm,
+        DXGI_FORMAT_BC6H_UF16 => Bc6hUfloat,
+        DXGI_FORMAT_BC6H_SF16 => Bc6hFloat,
+        DXGI_FORMAT_BC7_UNORM => Bc7Unorm,
+        DXGI_FORMAT_BC7_UNORM_SRGB => Bc7Srgb,
+
+        _ => return None,
+    };
+
+    Some(format)
+}
+
 #[derive(Debug, Clone)]
 pub struct DecomposedDxgiFormat {
     pub typeless: DXGI_FORMAT,
@@ -256,6 +376,19 @@ impl DecomposedDxgiFormat {
                 copy_srv: Some(DXGI_FORMAT_R32_FLOAT_X8X24_TYPELESS),
             },
 
+            // the resource is created typeless so that it can be both a depth target
+            // and sampled in shaders (e.g. shadow maps)
+            DXGI_FORMAT_D24_UNORM_S8_UINT => DecomposedDxgiFormat {
+                typeless: DXGI_FORMAT_R24G8_TYPELESS,
+                // TODO: depth or stencil?
+                srv: Some(DXGI_FORMAT_R24_UNORM_X8_TYPELESS),
+                rtv: None,
+                uav: None,
+                dsv: Some(format),
+                copy_uav: None,
+                copy_srv: Some(DXGI_FORMAT_R24_UNORM_X8_TYPELESS),
+            },
+
             DXGI_FORMAT_D32_FLOAT => DecomposedDxgiFormat {
                 typeless: DXGI_FORMAT_R32_TYPELESS,
                 srv: Some(DXGI_FORMAT_R32_FLOAT),
@@ -437,6 +570,17 @@ impl DecomposedDxgiFormat {
     }
 }
 
+/// Returns the swap effect and the sync interval used for the given present mode.
+pub fn map_present_mode(mode: PresentMode) -> (DXGI_SWAP_EFFECT, UINT) {
+    match mode {
+        PresentMode::Immediate => (DXGI_SWAP_EFFECT_DISCARD, 0),
+        // flip model lets the compositor pick the latest presented frame
+        PresentMode::Mailbox => (DXGI_SWAP_EFFECT_FLIP_DISCARD, 0),
+        PresentMode::Fifo |
+        PresentMode::Relaxed => (DXGI_SWAP_EFFECT_DISCARD, 1),
+    }
+}
+
 pub fn map_viewport(viewport: &Viewport) -> D3D11_VIEWPORT {
     D3D11_VIEWPORT {
         TopLeftX: viewport.rect.x as _,
@@ -749,3 +893,30 @@ pub fn map_filter(
     map_anisotropic(anisotropic)
 }
 
+#[test]
+fn test_reverse_map_format() {
+    for i in 1 .. hal::format::NUM_FORMATS {
+        let format: Format = unsafe { mem::transmute(i as u32) };
+        if let Some(dxgi_format) = map_format(format) {
+            assert_eq!(reverse_map_format(dxgi_format), Some(format));
+        }
+    }
+}
+
+#[test]
+fn test_needs_rgb_expansion() {
+    assert!(needs_rgb_expansion(Format::Rgb8Unorm));
+    assert!(needs_rgb_expansion(Format::Bgr8Srgb));
+    assert!(needs_rgb_expansion(Format::Rgb16Float));
+    assert!(!needs_rgb_expansion(Format::Rgba8Unorm));
+    assert!(!needs_rgb_expansion(Format::Rgb32Float));
+}
+
+#[test]
+fn test_map_backbuffer_format() {
+    // sRGB surfaces are backed by linear buffers
+    assert_eq!(map_backbuffer_format(Format::Bgra8Srgb), Some(DXGI_FORMAT_B8G8R8A8_UNORM));
+    assert_eq!(map_backbuffer_format(Format::Rgba8Srgb), Some(DXGI_FORMAT_R8G8B8A8_UNORM));
+    assert_eq!(map_backbuffer_format(Format::Rgba16Float), Some(DXGI_FORMAT_R16G16B16A16_FLOAT));
+    assert_eq!(map_backbuffer_format(Format::Rgba32Float), None);
+}
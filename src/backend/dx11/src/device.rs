@@ -14,7 +14,7 @@ use wio::com::ComPtr;
 use std::sync::Arc;
 use std::borrow::Borrow;
 use std::cell::RefCell;
-use std::mem;
+use std::{cmp, mem};
 use std::ops::Range;
 use std::ptr;
 
@@ -2183,18 +2183,17 @@ impl hal::Device<Backend> for Device {
 
         debug!("{:#?}", config);
 
-        let (non_srgb_format, format) = {
-            // NOTE: DXGI doesn't allow sRGB format on the swapchain, but
-            //       creating RTV of swapchain buffers with sRGB works
-            let format = match config.format {
-                format::Format::Bgra8Srgb => format::Format::Bgra8Unorm,
-                format::Format::Rgba8Srgb => format::Format::Rgba8Unorm,
-                format => format,
-            };
-
-            (map_format(format).unwrap(), map_format(config.format).unwrap())
-        };
+        // NOTE: DXGI doesn't allow sRGB format on the swapchain, but
+        //       creating RTV of swapchain buffers with sRGB works
+        let non_srgb_format = conv::map_backbuffer_format(config.format).unwrap();
+        let format = map_format(config.format).unwrap();
         let decomposed = conv::DecomposedDxgiFormat::from_dxgi_format(format);
+        let (swap_effect, sync_interval) = conv::map_present_mode(config.present_mode);
+        // flip model swap effects require at least two buffers
+        let buffer_count = match swap_effect {
+            DXGI_SWAP_EFFECT_DISCARD => config.image_count,
+            _ => cmp::max(config.image_count, 2),
+        };
 
         let mut desc = DXGI_SWAP_CHAIN_DESC {
             BufferDesc: dxgitype::DXGI_MODE_DESC {
@@ -2216,12 +2215,11 @@ impl hal::Device<Backend> for Device {
                 Quality: 0
             },
             BufferUsage: dxgitype::DXGI_USAGE_RENDER_TARGET_OUTPUT | dxgitype::DXGI_USAGE_SHADER_INPUT,
-            BufferCount: config.image_count,
+            BufferCount: buffer_count,
             OutputWindow: surface.wnd_handle,
             // TODO:
             Windowed: TRUE,
-            // TODO:
-            SwapEffect: DXGI_SWAP_EFFECT_DISCARD,
+            SwapEffect: swap_effect,
             Flags: 0
         };
         let swapchain = {
@@ -2301,7 +2299,7 @@ impl hal::Device<Backend> for Device {
             }
         }).collect();
 
-        (Swapchain { dxgi_swapchain: swapchain }, hal::Backbuffer::Images(images))
+        (Swapchain { dxgi_swapchain: swapchain, sync_interval }, hal::Backbuffer::Images(images))
     }
 
     fn destroy_swapchain(&self, _swapchain: Swapchain) {
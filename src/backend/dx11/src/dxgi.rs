@@ -13,7 +13,7 @@ use std::mem;
 use std::os::windows::ffi::OsStringExt;
 use std::ptr;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
 pub(crate) enum DxgiVersion {
     /// Capable of the following interfaces:
     ///   * IDXGIObject
@@ -89,6 +89,8 @@ mod internal;
 mod range_alloc;
 mod shader;
 
+pub use conv::needs_rgb_expansion;
+
 #[derive(Clone, Derivative)]
 #[derivative(Debug)]
 pub(crate) struct ViewInfo {
@@ -137,6 +139,7 @@ impl Instance {
 
         Surface {
             factory: self.factory.clone(),
+            dxgi_version: self.dxgi_version,
             wnd_handle: hwnd as *mut _,
             width: width,
             height: height,
@@ -646,6 +649,7 @@ impl hal::PhysicalDevice<Backend> for PhysicalDevice {
 
 pub struct Surface {
     pub(crate) factory: ComPtr<IDXGIFactory>,
+    pub(crate) dxgi_version: dxgi::DxgiVersion,
     wnd_handle: HWND,
     width: u32,
     height: u32,
@@ -692,18 +696,29 @@ impl hal::Surface<Backend> for Surface {
             usage: image::Usage::COLOR_ATTACHMENT | image::Usage::TRANSFER_SRC,
         };
 
-        let formats = vec![
-            format::Format::Bgra8Srgb,
-            format::Format::Bgra8Unorm,
-            format::Format::Rgba8Srgb,
-            format::Format::Rgba8Unorm,
-            format::Format::A2b10g10r10Unorm,
-            format::Format::Rgba16Float,
-        ];
+        // back buffer formats supported by both the bitblt and the flip model
+        // swap effects, sRGB ones are exposed through sRGB render target views
+        // (see `conv::map_backbuffer_format`)
+        let formats = [
+            dxgiformat::DXGI_FORMAT_B8G8R8A8_UNORM_SRGB,
+            dxgiformat::DXGI_FORMAT_B8G8R8A8_UNORM,
+            dxgiformat::DXGI_FORMAT_R8G8B8A8_UNORM_SRGB,
+            dxgiformat::DXGI_FORMAT_R8G8B8A8_UNORM,
+            dxgiformat::DXGI_FORMAT_R10G10B10A2_UNORM,
+            dxgiformat::DXGI_FORMAT_R16G16B16A16_FLOAT,
+        ]
+            .iter()
+            .filter_map(|&format| conv::reverse_map_format(format))
+            .collect();
 
-        let present_modes = vec![
-            hal::PresentMode::Fifo, //TODO
+        let mut present_modes = vec![
+            hal::PresentMode::Fifo,
+            hal::PresentMode::Immediate,
         ];
+        // DXGI_SWAP_EFFECT_FLIP_DISCARD requires Windows 10
+        if self.dxgi_version >= dxgi::DxgiVersion::Dxgi1_4 {
+            present_modes.push(hal::PresentMode::Mailbox);
+        }
 
         (capabilities, Some(formats), present_modes)
     }
@@ -711,6 +726,7 @@ impl hal::Surface<Backend> for Surface {
 
 pub struct Swapchain {
     dxgi_swapchain: ComPtr<IDXGISwapChain>,
+    sync_interval: UINT,
 }
 
 unsafe impl Send for Swapchain {}
@@ -805,7 +821,8 @@ impl hal::queue::RawCommandQueue<Backend> for CommandQueue {
     {
         for (swapchain, _idx) in swapchains {
             unsafe {
-                swapchain.borrow().dxgi_swapchain.Present(1, 0);
+                let swapchain = swapchain.borrow();
+                swapchain.dxgi_swapchain.Present(swapchain.sync_interval, 0);
             }
         }
 
@@ -17,7 +17,7 @@ use hal::{DrawCount, SwapImageIndex, VertexCount, VertexOffset, InstanceCount, I
 use hal::backend::FastHashMap;
 use hal::format::{Aspects, FormatDesc};
 use hal::image::{Extent, Filter, Layout, Level, SubresourceRange};
-use hal::pass::{AttachmentLoadOp};
+use hal::pass::{AttachmentLoadOp, AttachmentStoreOp};
 use hal::queue::{RawCommandQueue, RawSubmission};
 use hal::range::RangeArg;
 
@@ -189,6 +189,9 @@ struct RenderPipelineState {
 #[derive(Clone)]
 struct SubpassInfo {
     descriptor: metal::RenderPassDescriptor,
+    /// Separate pass resolving the color attachments at the end of the subpass,
+    /// if the device can't store and resolve them at the same time.
+    resolve_descriptor: Option<metal::RenderPassDescriptor>,
     combined_aspects: Aspects,
     formats: native::SubpassFormats,
 }
@@ -203,6 +206,12 @@ struct State {
     // Note: this could be `MTLViewport` but we have to patch the depth separately.
     viewport: Option<(pso::Rect, Range<f32>)>,
     scissors: Option<MTLScissorRect>,
+    /// All the viewports, only used when more than one is set.
+    viewport_array: Vec<MTLViewport>,
+    /// All the scissor rectangles, only used when more than one is set.
+    scissor_array: Vec<MTLScissorRect>,
+    /// Scissor rectangles clamped to the current render target extent.
+    clamped_scissor_array: Vec<MTLScissorRect>,
     blend_color: Option<pso::ColorValue>,
     render_pso: Option<RenderPipelineState>,
     /// A flag to handle edge cases of Vulkan binding inheritance:
@@ -211,6 +220,7 @@ struct State {
     compute_pso: Option<metal::ComputePipelineState>,
     work_group_size: MTLSize,
     primitive_type: MTLPrimitiveType,
+    primitive_restart: pso::PrimitiveRestart,
     resources_vs: StageResources,
     resources_ps: StageResources,
     resources_cs: StageResources,
@@ -225,6 +235,8 @@ struct State {
     target_formats: native::SubpassFormats,
     visibility_query: (metal::MTLVisibilityResultMode, buffer::Offset),
     pending_subpasses: Vec<SubpassInfo>,
+    /// Resolve pass to run after the active subpass.
+    pending_resolve: Option<metal::RenderPassDescriptor>,
 }
 
 impl State {
@@ -235,6 +247,7 @@ impl State {
         self.push_constants.clear();
         self.vertex_buffers.clear();
         self.pending_subpasses.clear();
+        self.pending_resolve = None;
     }
 
     fn clamp_scissor(sr: MTLScissorRect, extent: Extent) -> MTLScissorRect {
@@ -268,12 +281,20 @@ impl State {
         &'a self, aspects: Aspects
     ) -> impl Iterator<Item = soft::RenderCommand<&'a soft::Ref>> {
         // Apply previously bound values for this command buffer
-        let com_vp = self.viewport.as_ref().map(|&(rect, ref depth)| {
-            soft::RenderCommand::SetViewport(rect, depth.clone())
-        });
-        let com_scissor = self.scissors.map(|sr| soft::RenderCommand::SetScissor(
-            Self::clamp_scissor(sr, self.target_extent)
-        ));
+        let com_vp = if self.viewport_array.is_empty() {
+            self.viewport.as_ref().map(|&(rect, ref depth)| {
+                soft::RenderCommand::SetViewport(rect, depth.clone())
+            })
+        } else {
+            Some(soft::RenderCommand::SetViewports(&self.viewport_array[..]))
+        };
+        let com_scissor = if self.clamped_scissor_array.is_empty() {
+            self.scissors.map(|sr| soft::RenderCommand::SetScissor(
+                Self::clamp_scissor(sr, self.target_extent)
+            ))
+        } else {
+            Some(soft::RenderCommand::SetScissors(&self.clamped_scissor_array[..]))
+        };
         let com_blend = if aspects.contains(Aspects::COLOR) {
             self.blend_color.map(soft::RenderCommand::SetBlendColor)
         } else {
@@ -368,6 +389,66 @@ impl State {
     }
 
     fn set_vertex_buffers(&mut self) -> Option<soft::RenderCommand<&soft::Ref>> {
+        self.set_vertex_buffers_at(0, 0)
+    }
+
+    /// Metal restarts strips at the all-ones value of the bound index type,
+    /// which is not the requested restart value if the index sizes differ.
+    fn check_primitive_restart(&self) {
+        let restart_stride = match self.primitive_restart {
+            pso::PrimitiveRestart::Disabled => return,
+            pso::PrimitiveRestart::U16 => 2,
+            pso::PrimitiveRestart::U32 => 4,
+        };
+        match (self.primitive_type, self.index_buffer) {
+            (MTLPrimitiveType::LineStrip, Some(ref index)) |
+            (MTLPrimitiveType::TriangleStrip, Some(ref index)) if index.stride != restart_stride => {
+                warn!("Primitive restart {:?} doesn't match the index stride {}, restarting at the maximum index instead",
+                    self.primitive_restart, index.stride);
+            }
+            _ => {}
+        }
+    }
+
+    /// Check if the vertex buffers can be shifted to the given base vertex
+    /// without moving any of their offsets below zero.
+    fn can_shift_vertex_buffers(&self, base_vertex: VertexOffset) -> bool {
+        let rps = match self.render_pso {
+            Some(ref rps) => rps,
+            None => return true,
+        };
+        rps.vertex_buffers.iter().all(|vb_maybe| match *vb_maybe {
+            Some((ref vb, extra_offset)) if vb.rate == 0 => {
+                match self.vertex_buffers.get(vb.binding as usize) {
+                    Some(&Some((_, base_offset))) => {
+                        extra_offset as i64 + base_offset as i64 + base_vertex as i64 * vb.stride as i64 >= 0
+                    }
+                    _ => true,
+                }
+            }
+            _ => true,
+        })
+    }
+
+    /// Check if the given base instance is a multiple of all the instance step rates,
+    /// so that shifting the per-instance vertex buffers by whole elements is exact.
+    fn is_instance_aligned(&self, base_instance: InstanceCount) -> bool {
+        let rps = match self.render_pso {
+            Some(ref rps) => rps,
+            None => return true,
+        };
+        rps.vertex_buffers.iter().all(|vb_maybe| match *vb_maybe {
+            Some((ref vb, _)) if vb.rate > 1 => base_instance % vb.rate as InstanceCount == 0,
+            _ => true,
+        })
+    }
+
+    /// Bind the vertex buffers with their offsets shifted to the given base vertex
+    /// and instance, for devices that can't pass those to the draw calls.
+    /// Per-instance buffers are shifted by whole elements, rounding the base instance down.
+    fn set_vertex_buffers_at(
+        &mut self, base_vertex: VertexOffset, base_instance: InstanceCount,
+    ) -> Option<soft::RenderCommand<&soft::Ref>> {
         let rps = self.render_pso.as_ref()?;
         let start = rps.attribute_buffer_index as usize;
         let end = start + rps.vertex_buffers.len();
@@ -382,8 +463,15 @@ impl State {
                 Some((ref vb, extra_offset)) => {
                     match self.vertex_buffers.get(vb.binding as usize) {
                         Some(&Some((buffer, base_offset))) => {
+                            let base_element = if vb.rate == 0 {
+                                base_vertex as i64
+                            } else {
+                                (base_instance / vb.rate as InstanceCount) as i64
+                            };
+                            let offset = *extra_offset as i64 + base_offset as i64 + base_element * vb.stride as i64;
+                            debug_assert!(offset >= 0, "checked by `can_shift_vertex_buffers`");
                             *out_buffer = Some(buffer);
-                            *out_offset = *extra_offset as u64 + base_offset;
+                            *out_offset = offset as u64;
                         }
                         _ => {
                             // being unable to bind a buffer here is technically fine, since before this moment
@@ -469,30 +557,80 @@ impl State {
         }
     }
 
-    fn set_viewport<'a>(
-        &mut self, vp: &'a pso::Viewport, disabilities: &PrivateDisabilities
-    ) -> soft::RenderCommand<&'a soft::Ref> {
-        let depth = vp.depth.start .. if disabilities.broken_viewport_near_depth {
+    fn patch_viewport_depth(vp: &pso::Viewport, disabilities: &PrivateDisabilities) -> Range<f32> {
+        vp.depth.start .. if disabilities.broken_viewport_near_depth {
             (vp.depth.end - vp.depth.start)
         } else {
             vp.depth.end
-        };
+        }
+    }
+
+    fn set_viewport<'a>(
+        &mut self, vp: &'a pso::Viewport, disabilities: &PrivateDisabilities
+    ) -> soft::RenderCommand<&'a soft::Ref> {
+        let depth = Self::patch_viewport_depth(vp, disabilities);
         self.viewport = Some((vp.rect, depth.clone()));
+        self.viewport_array.clear();
         soft::RenderCommand::SetViewport(vp.rect, depth)
     }
 
+    fn set_viewport_array<'a>(
+        &'a mut self, first: usize, vps: &[pso::Viewport], disabilities: &PrivateDisabilities
+    ) -> soft::RenderCommand<&'a soft::Ref> {
+        // Metal can only set the whole array at once, so we start with what was set before
+        if self.viewport_array.is_empty() {
+            if let Some((ref rect, ref depth)) = self.viewport {
+                self.viewport_array.push(conv::map_viewport(rect, depth));
+            }
+        }
+        if self.viewport_array.len() < first + vps.len() {
+            let default = conv::map_viewport(&pso::Rect { x: 0, y: 0, w: 0, h: 0 }, &(0.0 .. 1.0));
+            self.viewport_array.resize(first + vps.len(), default);
+        }
+        for (dst, vp) in self.viewport_array[first ..].iter_mut().zip(vps) {
+            *dst = conv::map_viewport(&vp.rect, &Self::patch_viewport_depth(vp, disabilities));
+        }
+        self.viewport = None;
+        soft::RenderCommand::SetViewports(&self.viewport_array[..])
+    }
+
     fn set_scissor<'a>(&mut self, rect: &'a pso::Rect) -> soft::RenderCommand<&'a soft::Ref> {
-        let scissor = MTLScissorRect {
-            x: rect.x as _,
-            y: rect.y as _,
-            width: rect.w as _,
-            height: rect.h as _,
-        };
+        let scissor = conv::map_scissor(rect);
         self.scissors = Some(scissor);
+        self.scissor_array.clear();
+        self.clamped_scissor_array.clear();
         let clamped = State::clamp_scissor(scissor, self.target_extent);
         soft::RenderCommand::SetScissor(clamped)
     }
 
+    fn set_scissor_array<'a>(
+        &'a mut self, first: usize, rects: &[pso::Rect]
+    ) -> soft::RenderCommand<&'a soft::Ref> {
+        if self.scissor_array.is_empty() {
+            if let Some(scissor) = self.scissors {
+                self.scissor_array.push(scissor);
+            }
+        }
+        if self.scissor_array.len() < first + rects.len() {
+            let default = conv::map_scissor(&pso::Rect { x: 0, y: 0, w: 0, h: 0 });
+            self.scissor_array.resize(first + rects.len(), default);
+        }
+        for (dst, rect) in self.scissor_array[first ..].iter_mut().zip(rects) {
+            *dst = conv::map_scissor(rect);
+        }
+        self.scissors = None;
+        self.update_clamped_scissors();
+        soft::RenderCommand::SetScissors(&self.clamped_scissor_array[..])
+    }
+
+    fn update_clamped_scissors(&mut self) {
+        let extent = self.target_extent;
+        self.clamped_scissor_array.clear();
+        self.clamped_scissor_array.extend(
+            self.scissor_array.iter().map(|&sr| Self::clamp_scissor(sr, extent))
+        );
+    }
+
     fn set_blend_color<'a>(&mut self, color: &'a pso::ColorValue) -> soft::RenderCommand<&'a soft::Ref> {
         self.blend_color = Some(*color);
         soft::RenderCommand::SetBlendColor(*color)
@@ -522,6 +660,32 @@ impl State {
 }
 
 #[derive(Clone, Debug)]
+/// Returns the pool indices of the dynamic buffers of an emulated descriptor set,
+/// in the order their dynamic offsets are provided.
+fn dynamic_buffer_indices<'a>(
+    layouts: &'a [native::DescriptorLayout], first: native::PoolResourceIndex,
+) -> impl Iterator<Item = native::PoolResourceIndex> + 'a {
+    layouts
+        .iter()
+        .filter(|layout| layout.content.contains(native::DescriptorContent::BUFFER))
+        .zip(first ..)
+        .filter(|&(layout, _)| layout.content.contains(native::DescriptorContent::DYNAMIC_BUFFER))
+        .map(|(_, index)| index)
+}
+
+/// Checks that a dynamic offset keeps the range of a buffer descriptor within its buffer.
+fn is_dynamic_offset_valid(
+    data: &native::DescriptorPoolInner, pool_index: native::PoolResourceIndex, offset: buffer::Offset,
+) -> bool {
+    match data.buffers[pool_index as usize] {
+        Some((ref buffer, ref range)) if range.end + offset > buffer.as_native().length() => {
+            error!("Dynamic offset {} moves the descriptor range {:?} out of bounds of the buffer", offset, range);
+            false
+        }
+        _ => true,
+    }
+}
+
 struct StageResources {
     buffers: Vec<Option<BufferPtr>>,
     buffer_offsets: Vec<buffer::Offset>,
@@ -589,7 +753,7 @@ impl StageResources {
                 }
                 if layout.content.contains(native::DescriptorContent::BUFFER) {
                     let (buffer, offset) = match data.buffers[pool_offsets.buffers as usize] {
-                        Some((buffer, offset)) => (Some(buffer), offset),
+                        Some((buffer, ref range)) => (Some(buffer), range.start),
                         None => (None, 0),
                     };
                     self.buffers[res_offset.buffers as usize] = buffer;
@@ -1167,11 +1331,40 @@ fn compute_pitches(
     } else {
         region.buffer_height
     };
-    let row_pitch = div(buffer_width, fd.dim.0 as _) * (fd.bits / 8) as u32;
+    let bytes_per_block = if fd.aspects.contains(Aspects::DEPTH | Aspects::STENCIL) {
+        // combined formats are copied one aspect at a time, see `map_blit_option`
+        if region.image_layers.aspects.contains(Aspects::DEPTH) { 4 } else { 1 }
+    } else {
+        fd.bits as u32 / 8
+    };
+    let row_pitch = div(buffer_width, fd.dim.0 as _) * bytes_per_block;
     let slice_pitch = div(buffer_height, fd.dim.1 as _) * row_pitch;
     (row_pitch, slice_pitch)
 }
 
+/// Metal requires copies between buffers and combined depth-stencil textures
+/// to specify which of the aspects is being transferred.
+fn map_blit_option(fd: &FormatDesc, aspects: Aspects) -> metal::MTLBlitOption {
+    if !fd.aspects.contains(Aspects::DEPTH | Aspects::STENCIL) {
+        metal::MTLBlitOption::empty()
+    } else if aspects.contains(Aspects::DEPTH) {
+        metal::MTLBlitOption::DepthFromDepthStencil
+    } else {
+        metal::MTLBlitOption::StencilFromDepthStencil
+    }
+}
+
+/// Checks that a buffer-image copy region can be expressed as a single Metal blit.
+fn is_valid_buffer_image_copy(fd: &FormatDesc, region: &com::BufferImageCopy) -> bool {
+    if fd.aspects.contains(Aspects::DEPTH | Aspects::STENCIL) &&
+        region.image_layers.aspects.contains(Aspects::DEPTH | Aspects::STENCIL)
+    {
+        error!("Unable to copy both depth and stencil aspects of {:?} at once", fd);
+        return false;
+    }
+    true
+}
+
 fn exec_render<R, C>(encoder: &metal::RenderCommandEncoderRef, command: C, resources: &R)
 where
     R: soft::Resources,
@@ -1179,6 +1372,8 @@ where
     R::BufferArray: soft::AsSlice<Option<BufferPtr>, R> + soft::AsSlice<buffer::Offset, R>,
     R::TextureArray: soft::AsSlice<Option<TexturePtr>, R>,
     R::SamplerArray: soft::AsSlice<Option<SamplerPtr>, R>,
+    R::ViewportArray: soft::AsSlice<MTLViewport, R>,
+    R::ScissorArray: soft::AsSlice<MTLScissorRect, R>,
     R::DepthStencil: Borrow<metal::DepthStencilStateRef>,
     R::RenderPipeline: Borrow<metal::RenderPipelineStateRef>,
     C: Borrow<soft::RenderCommand<R>>,
@@ -1186,18 +1381,25 @@ where
     use soft::RenderCommand as Cmd;
     match *command.borrow() {
         Cmd::SetViewport(ref rect, ref depth) => {
-            encoder.set_viewport(MTLViewport {
-                originX: rect.x as _,
-                originY: rect.y as _,
-                width: rect.w as _,
-                height: rect.h as _,
-                znear: depth.start as _,
-                zfar: depth.end as _,
-            });
+            encoder.set_viewport(conv::map_viewport(rect, depth));
         }
         Cmd::SetScissor(scissor) => {
             encoder.set_scissor_rect(scissor);
         }
+        Cmd::SetViewports(ref viewports) => {
+            use soft::AsSlice;
+            let viewports = viewports.as_slice(resources);
+            let () = unsafe {
+                msg_send![encoder, setViewports: viewports.as_ptr() count: viewports.len() as NSUInteger]
+            };
+        }
+        Cmd::SetScissors(ref scissors) => {
+            use soft::AsSlice;
+            let scissors = scissors.as_slice(resources);
+            let () = unsafe {
+                msg_send![encoder, setScissorRects: scissors.as_ptr() count: scissors.len() as NSUInteger]
+            };
+        }
         Cmd::SetBlendColor(color) => {
             encoder.set_blend_color(color[0], color[1], color[2], color[3]);
         }
@@ -1445,7 +1647,7 @@ where
                     layer as NSUInteger,
                     r.level as NSUInteger,
                     origin,
-                    metal::MTLBlitOption::empty(),
+                    map_blit_option(&dst_desc, r.aspects),
                 );
             }
         }
@@ -1467,7 +1669,7 @@ where
                     offset as NSUInteger,
                     row_pitch as NSUInteger,
                     slice_pitch as NSUInteger,
-                    metal::MTLBlitOption::empty(),
+                    map_blit_option(&src_desc, r.aspects),
                 );
             }
         }
@@ -1631,15 +1833,34 @@ impl RawCommandQueue<Backend> for CommandQueue {
             .into_iter()
             .filter_map(|sem| sem.system.clone())
             .collect::<SmallVec<[_; BLOCK_BUCKET]>>();
+        let signal_events = submit.signal_semaphores
+            .into_iter()
+            .filter_map(|sem| sem.event.as_ref())
+            .collect::<SmallVec<[_; BLOCK_BUCKET]>>();
 
         #[allow(unused_mut)]
         let (mut num_immediate, mut num_deferred, mut num_remote) = (0, 0, 0);
-        let do_signal = fence.is_some() || !system_semaphores.is_empty();
+        let do_signal = fence.is_some() || !system_semaphores.is_empty() || !signal_events.is_empty();
 
         autoreleasepool(|| { // for command buffers
             let cmd_queue = self.shared.queue.lock();
             let mut deferred_cmd_buffer = None::<&metal::CommandBufferRef>;
 
+            // command buffers of the queue are executed in order,
+            // so the waits are encoded ahead of everything else
+            let mut wait_events = submit.wait_semaphores
+                .iter()
+                .filter_map(|&(sem, _)| sem.event.as_ref())
+                .peekable();
+            if wait_events.peek().is_some() {
+                let cmd_buffer = cmd_queue.spawn_temp();
+                cmd_buffer.set_label("wait");
+                for event in wait_events {
+                    event.wait(cmd_buffer);
+                }
+                cmd_buffer.commit();
+            }
+
             for buffer in submit.cmd_buffers {
                 let mut inner = buffer.borrow().inner.borrow_mut();
                 let CommandBufferInner {
@@ -1746,6 +1967,12 @@ impl RawCommandQueue<Backend> for CommandQueue {
                         self.record_empty(cmd_buffer);
                         cmd_buffer
                     });
+                for event in &signal_events {
+                    event.signal(cmd_buffer);
+                }
+                if let Some(&native::Fence(_, Some(ref event))) = fence {
+                    event.signal(cmd_buffer);
+                }
                 msg_send![cmd_buffer, addCompletedHandler: block.deref() as *const _];
                 cmd_buffer.commit();
 
@@ -1786,7 +2013,16 @@ impl RawCommandQueue<Backend> for CommandQueue {
 
             for (swapchain, index) in swapchains {
                 debug!("presenting frame {}", index);
-                let drawable = swapchain.borrow().take_drawable(index)?;
+                let swapchain = swapchain.borrow();
+                let drawable = swapchain.take_drawable(index)?;
+                if let Some((proxy, texture)) = swapchain.proxy_blit(index) {
+                    self.shared.service_pipes.simple_blit(
+                        &self.shared.device,
+                        command_buffer,
+                        proxy,
+                        texture,
+                    );
+                }
                 command_buffer.present_drawable(&drawable);
             }
             command_buffer.commit();
@@ -1860,12 +2096,16 @@ impl pool::RawCommandPool<Backend> for CommandPool {
             state: State {
                 viewport: None,
                 scissors: None,
+                viewport_array: Vec::new(),
+                scissor_array: Vec::new(),
+                clamped_scissor_array: Vec::new(),
                 blend_color: None,
                 render_pso: None,
                 render_pso_is_compatible: false,
                 compute_pso: None,
                 work_group_size: MTLSize { width: 0, height: 0, depth: 0 },
                 primitive_type: MTLPrimitiveType::Point,
+                primitive_restart: pso::PrimitiveRestart::Disabled,
                 resources_vs: StageResources::new(),
                 resources_ps: StageResources::new(),
                 resources_cs: StageResources::new(),
@@ -1887,6 +2127,7 @@ impl pool::RawCommandPool<Backend> for CommandPool {
                 target_formats: native::SubpassFormats::default(),
                 visibility_query: (metal::MTLVisibilityResultMode::Disabled, 0),
                 pending_subpasses: Vec::new(),
+                pending_resolve: None,
             },
             temp: Temp {
                 clear_vertices: Vec::new(),
@@ -1919,6 +2160,54 @@ impl pool::RawCommandPool<Backend> for CommandPool {
 }
 
 impl CommandBuffer {
+    /// Issue a draw with the vertex buffers temporarily shifted by the base
+    /// vertex and instance, restoring the regular bindings afterwards.
+    ///
+    /// Note: the shaders see `[[vertex_id]]` and `[[instance_id]]` without the base,
+    /// unlike the native path.
+    fn issue_with_base(
+        &mut self,
+        base_vertex: VertexOffset,
+        command: soft::RenderCommand<&soft::Ref>,
+    ) {
+        if !self.state.can_shift_vertex_buffers(base_vertex) {
+            error!("Base vertex {} moves the vertex buffer offsets below zero, skipping the draw", base_vertex);
+            return
+        }
+        let instances = match command {
+            soft::RenderCommand::Draw { ref instances, .. } |
+            soft::RenderCommand::DrawIndexed { ref instances, .. } => instances.clone(),
+            _ => unreachable!(),
+        };
+
+        let mut inner = self.inner.borrow_mut();
+        let mut pre = inner.sink().pre_render();
+        let mut start = instances.start;
+        while start < instances.end {
+            // per-instance buffers can only be shifted by whole elements, so the instances
+            // preceding a multiple of all the step rates are drawn one by one
+            let end = if self.state.is_instance_aligned(start) {
+                instances.end
+            } else {
+                start + 1
+            };
+            if let Some(com) = self.state.set_vertex_buffers_at(base_vertex, start) {
+                pre.issue(com);
+            }
+            let mut com = command.clone();
+            match com {
+                soft::RenderCommand::Draw { ref mut instances, .. } |
+                soft::RenderCommand::DrawIndexed { ref mut instances, .. } => *instances = 0 .. end - start,
+                _ => unreachable!(),
+            }
+            pre.issue(com);
+            start = end;
+        }
+        if let Some(com) = self.state.set_vertex_buffers() {
+            pre.issue(com);
+        }
+    }
+
     fn update_depth_stencil(&self) {
         let mut inner = self.inner.borrow_mut();
         let mut pre = inner.sink().pre_render();
@@ -2310,11 +2599,13 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
             *out = mtl_format;
         }
 
+        let mut stencil_is_dirty = false;
         for clear in clears {
             let pso; // has to live at least as long as all the commands
             let depth_stencil;
             let raw_value;
 
+            let mut com_stencil = None;
             let (com_clear, target_index) = match *clear.borrow() {
                 com::AttachmentClear::Color { index, value } => {
                     let channel = self.state.target_formats.colors[index].1;
@@ -2341,8 +2632,9 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
                         vertex_is_dirty = true;
                         aspects |= Aspects::DEPTH;
                     }
-                    if let Some(_) = stencil {
-                        //TODO: soft::RenderCommand::SetStencilReference
+                    if let Some(value) = stencil {
+                        com_stencil = Some(soft::RenderCommand::SetStencilReferenceValues(value, value));
+                        stencil_is_dirty = true;
                         aspects |= Aspects::STENCIL;
                     }
                     depth_stencil = ds_store.get_write(aspects);
@@ -2383,6 +2675,7 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
             });
 
             let commands = iter::once(com_clear)
+                .chain(com_stencil)
                 .chain(com_pso)
                 .chain(com_rast)
                 .chain(com_vertex)
@@ -2405,6 +2698,14 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
             }
             None => None,
         };
+        let com_stencil = if stencil_is_dirty {
+            Some(soft::RenderCommand::SetStencilReferenceValues(
+                self.state.stencil.front_reference,
+                self.state.stencil.back_reference,
+            ))
+        } else {
+            None
+        };
 
         let com_vs = match (self.state.resources_vs.buffers.first(), self.state.resources_vs.buffer_offsets.first()) {
             (Some(&Some(buffer)), Some(&offset)) => Some(soft::RenderCommand::BindBuffer {
@@ -2429,6 +2730,7 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
             .into_iter()
             .chain(com_rast)
             .chain(com_ds)
+            .chain(com_stencil)
             .chain(com_vs)
             .chain(com_ps);
 
@@ -2727,19 +3029,21 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
         T: IntoIterator,
         T::Item: Borrow<pso::Viewport>,
     {
-        // macOS_GPUFamily1_v3 supports >1 viewport, todo
-        if first_viewport != 0 {
-            panic!("First viewport != 0; Metal supports only one viewport");
-        }
-        let mut vps = vps.into_iter();
-        let vp_borrowable = vps.next().expect("No viewport provided, Metal supports exactly one");
-        let vp = vp_borrowable.borrow();
-        if vps.next().is_some() {
-            // TODO should we panic here or set buffer in an erroneous state?
-            panic!("More than one viewport set; Metal supports only one viewport");
-        }
-
-        let com = self.state.set_viewport(vp, &self.shared.disabilities);
+        let vps: SmallVec<[pso::Viewport; 1]> = vps
+            .into_iter()
+            .map(|vp| vp.borrow().clone())
+            .collect();
+        assert!(!vps.is_empty(), "No viewport provided");
+        assert!(first_viewport as usize + vps.len() <= self.shared.max_viewports,
+            "Device supports at most {} viewports", self.shared.max_viewports);
+
+        // keep the fast path for the common case of a single viewport,
+        // unless an array is already bound and the other entries need to stay
+        let com = if first_viewport == 0 && vps.len() == 1 && self.state.viewport_array.is_empty() {
+            self.state.set_viewport(&vps[0], &self.shared.disabilities)
+        } else {
+            self.state.set_viewport_array(first_viewport as usize, &vps, &self.shared.disabilities)
+        };
         self.inner
             .borrow_mut()
             .sink()
@@ -2752,18 +3056,19 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
         T: IntoIterator,
         T::Item: Borrow<pso::Rect>,
     {
-        // macOS_GPUFamily1_v3 supports >1 scissor/viewport, todo
-        if first_scissor != 0 {
-            panic!("First scissor != 0; Metal supports only one viewport");
-        }
-        let mut rects = rects.into_iter();
-        let rect_borrowable = rects.next().expect("No scissor provided, Metal supports exactly one");
-        let rect = rect_borrowable.borrow();
-        if rects.next().is_some() {
-            panic!("More than one scissor set; Metal supports only one viewport");
-        }
-
-        let com = self.state.set_scissor(rect);
+        let rects: SmallVec<[pso::Rect; 1]> = rects
+            .into_iter()
+            .map(|rect| rect.borrow().clone())
+            .collect();
+        assert!(!rects.is_empty(), "No scissor provided");
+        assert!(first_scissor as usize + rects.len() <= self.shared.max_viewports,
+            "Device supports at most {} scissor rectangles", self.shared.max_viewports);
+
+        let com = if first_scissor == 0 && rects.len() == 1 && self.state.scissor_array.is_empty() {
+            self.state.set_scissor(&rects[0])
+        } else {
+            self.state.set_scissor_array(first_scissor as usize, &rects)
+        };
         self.inner
             .borrow_mut()
             .sink()
@@ -2862,11 +3167,13 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
 
         self.state.pending_subpasses.clear();
         self.state.target_extent = framebuffer.extent;
+        self.state.update_clamped_scissors();
 
         //TODO: cache produced `RenderPassDescriptor` objects
         // we stack the subpasses in the opposite order
         for subpass in render_pass.subpasses.iter().rev() {
             let mut combined_aspects = Aspects::empty();
+            let mut resolve_descriptor = None;
             let descriptor = metal::RenderPassDescriptor::new().to_owned();
             descriptor.set_visibility_result_buffer(Some(&self.shared.visibility.buffer));
             descriptor.set_render_target_array_length(framebuffer.extent.depth as _);
@@ -2890,7 +3197,35 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
                         desc.set_clear_color(channel.interpret(raw));
                     }
                 }
-                if op_flags.contains(native::SubpassOps::STORE) {
+                if let Some(&resolve_id) = subpass.resolves.get(i) {
+                    // keep the multisampled contents around if a later subpass needs them
+                    let store = if op_flags.contains(native::SubpassOps::STORE) {
+                        rat.ops.store
+                    } else {
+                        AttachmentStoreOp::Store
+                    };
+                    let resolve_texture = &framebuffer.attachments[resolve_id];
+                    if store == AttachmentStoreOp::Store && self.shared.disabilities.no_store_and_resolve {
+                        // store now, and resolve in a separate pass that only loads the contents
+                        desc.set_store_action(metal::MTLStoreAction::Store);
+                        let resolve_desc = resolve_descriptor
+                            .get_or_insert_with(|| {
+                                let descriptor = metal::RenderPassDescriptor::new().to_owned();
+                                descriptor.set_render_target_array_length(framebuffer.extent.depth as _);
+                                descriptor
+                            })
+                            .color_attachments()
+                            .object_at(i as _)
+                            .unwrap();
+                        resolve_desc.set_texture(Some(texture));
+                        resolve_desc.set_load_action(metal::MTLLoadAction::Load);
+                        resolve_desc.set_resolve_texture(Some(resolve_texture));
+                        resolve_desc.set_store_action(metal::MTLStoreAction::MultisampleResolve);
+                    } else {
+                        desc.set_resolve_texture(Some(resolve_texture));
+                        desc.set_store_action(conv::map_resolve_store_operation(store));
+                    }
+                } else if op_flags.contains(native::SubpassOps::STORE) {
                     desc.set_store_action(conv::map_store_operation(rat.ops.store));
                 }
             }
@@ -2939,6 +3274,7 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
 
             self.state.pending_subpasses.push(SubpassInfo {
                 descriptor,
+                resolve_descriptor,
                 combined_aspects,
                 formats: subpass.target_formats.clone(),
             });
@@ -2949,6 +3285,7 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
 
     fn next_subpass(&mut self, _contents: com::SubpassContents) {
         let sin = self.state.pending_subpasses.pop().unwrap();
+        let finished_resolve = mem::replace(&mut self.state.pending_resolve, sin.resolve_descriptor);
 
         self.state.render_pso_is_compatible = match self.state.render_pso {
             Some(ref ps) => ps.formats == sin.formats,
@@ -2975,18 +3312,22 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
             .make_render_commands(sin.combined_aspects)
             .chain(com_ds);
 
-        self.inner
-            .borrow_mut()
-            .sink()
-            .switch_render(sin.descriptor)
+        let mut inner = self.inner.borrow_mut();
+        let sink = inner.sink();
+        if let Some(descriptor) = finished_resolve {
+            sink.quick_render("resolve", descriptor, iter::empty());
+        }
+        sink.switch_render(sin.descriptor)
             .issue_many(init_commands);
     }
 
     fn end_render_pass(&mut self) {
-        self.inner
-            .borrow_mut()
-            .sink()
-            .stop_encoding();
+        let mut inner = self.inner.borrow_mut();
+        let sink = inner.sink();
+        sink.stop_encoding();
+        if let Some(descriptor) = self.state.pending_resolve.take() {
+            sink.quick_render("resolve", descriptor, iter::empty());
+        }
     }
 
     fn bind_graphics_pipeline(&mut self, pipeline: &native::GraphicsPipeline) {
@@ -3047,6 +3388,7 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
         if set_pipeline {
             self.state.rasterizer_state = pipeline.rasterizer_state.clone();
             self.state.primitive_type = pipeline.primitive_type;
+            self.state.primitive_restart = pipeline.primitive_restart;
 
             pre.issue(soft::RenderCommand::BindPipeline(&*pipeline.raw));
             if let Some(ref rs) = pipeline.rasterizer_state {
@@ -3157,12 +3499,17 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
                     let end_ps_offsets = self.state.resources_ps.bind_set(pso::ShaderStageFlags::FRAGMENT, &*data, info.offsets.ps.clone(), layouts, resources);
                     bind_range.ps.expand(end_ps_offsets);
 
-                    for (dyn_data, offset) in info.dynamic_buffers.iter().zip(dynamic_offset_iter.by_ref()) {
+                    let dynamic_indices = dynamic_buffer_indices(layouts, resources.buffers.start);
+                    for ((dyn_data, offset), pool_index) in info.dynamic_buffers.iter().zip(dynamic_offset_iter.by_ref()).zip(dynamic_indices) {
+                        let offset = *offset.borrow() as buffer::Offset;
+                        if !is_dynamic_offset_valid(&*data, pool_index, offset) {
+                            continue
+                        }
                         if dyn_data.vs != !0 {
-                            self.state.resources_vs.buffer_offsets[dyn_data.vs as usize] += *offset.borrow() as buffer::Offset;
+                            self.state.resources_vs.buffer_offsets[dyn_data.vs as usize] += offset;
                         }
                         if dyn_data.ps != !0 {
-                            self.state.resources_ps.buffer_offsets[dyn_data.ps as usize] += *offset.borrow() as buffer::Offset;
+                            self.state.resources_ps.buffer_offsets[dyn_data.ps as usize] += offset;
                         }
                     }
                 }
@@ -3272,9 +3619,14 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
                     let end_offsets = cache.bind_set(pso::ShaderStageFlags::COMPUTE, &*data, res_offset.clone(), layouts, resources);
                     bind_range.expand(end_offsets);
 
-                    for (dyn_data, offset) in info.dynamic_buffers.iter().zip(dynamic_offset_iter.by_ref()) {
+                    let dynamic_indices = dynamic_buffer_indices(layouts, resources.buffers.start);
+                    for ((dyn_data, offset), pool_index) in info.dynamic_buffers.iter().zip(dynamic_offset_iter.by_ref()).zip(dynamic_indices) {
+                        let offset = *offset.borrow() as buffer::Offset;
+                        if !is_dynamic_offset_valid(&*data, pool_index, offset) {
+                            continue
+                        }
                         if dyn_data.cs != !0 {
-                            cache.buffer_offsets[dyn_data.cs as usize] += *offset.borrow() as buffer::Offset;
+                            cache.buffer_offsets[dyn_data.cs as usize] += offset;
                         }
                     }
                 }
@@ -3344,7 +3696,7 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
         pre.issue(soft::ComputeCommand::DispatchIndirect {
             wg_size: self.state.work_group_size,
             buffer: AsNative::from(buffer.raw.as_ref()),
-            offset,
+            offset: buffer.range.start + offset,
         });
     }
 
@@ -3482,11 +3834,11 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
                     .blit_commands(commands);
             }
             (&native::ImageLike::Buffer(ref src_buffer), &native::ImageLike::Texture(_)) => {
-                let src_extent = src.kind.extent();
                 self.copy_buffer_to_image(src_buffer, dst, dst_layout, regions.into_iter().map(|region| {
                     let r = region.borrow();
+                    let src_extent = src.kind.extent().at_level(r.src_subresource.level);
                     com::BufferImageCopy {
-                        buffer_offset: src.byte_offset(r.src_offset),
+                        buffer_offset: src.byte_offset(r.src_subresource.level, r.src_offset),
                         buffer_width: src_extent.width,
                         buffer_height: src_extent.height,
                         image_layers: r.dst_subresource.clone(),
@@ -3496,11 +3848,11 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
                 }))
             }
             (&native::ImageLike::Texture(_), &native::ImageLike::Buffer(ref dst_buffer)) => {
-                let dst_extent = dst.kind.extent();
                 self.copy_image_to_buffer(src, src_layout, dst_buffer, regions.into_iter().map(|region| {
                     let r = region.borrow();
+                    let dst_extent = dst.kind.extent().at_level(r.dst_subresource.level);
                     com::BufferImageCopy {
-                        buffer_offset: dst.byte_offset(r.dst_offset),
+                        buffer_offset: dst.byte_offset(r.dst_subresource.level, r.dst_offset),
                         buffer_width: dst_extent.width,
                         buffer_height: dst_extent.height,
                         image_layers: r.src_subresource.clone(),
@@ -3513,8 +3865,8 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
                 self.copy_buffer(src_buffer, dst_buffer, regions.into_iter().map(|region| {
                     let r = region.borrow();
                     com::BufferCopy {
-                        src: src.byte_offset(r.src_offset),
-                        dst: dst.byte_offset(r.dst_offset),
+                        src: src.byte_offset(r.src_subresource.level, r.src_offset),
+                        dst: dst.byte_offset(r.dst_subresource.level, r.dst_offset),
                         size: src.byte_extent(r.extent),
                     }
                 }))
@@ -3536,7 +3888,7 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
             native::ImageLike::Texture(ref dst_raw) => {
                 let commands = regions.into_iter().filter_map(|region| {
                     let r = region.borrow();
-                    if r.image_extent.is_empty() {
+                    if r.image_extent.is_empty() || !is_valid_buffer_image_copy(&dst.format_desc, r) {
                         None
                     } else {
                         Some(soft::BlitCommand::CopyBufferToImage {
@@ -3560,7 +3912,7 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
                     let r = region.borrow();
                     com::BufferCopy {
                         src: r.buffer_offset,
-                        dst: dst.byte_offset(r.image_offset),
+                        dst: dst.byte_offset(r.image_layers.level, r.image_offset),
                         size: dst.byte_extent(r.image_extent),
                     }
                 }))
@@ -3582,7 +3934,7 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
             native::ImageLike::Texture(ref src_raw) => {
                 let commands = regions.into_iter().filter_map(|region| {
                     let r = region.borrow();
-                    if r.image_extent.is_empty() {
+                    if r.image_extent.is_empty() || !is_valid_buffer_image_copy(&src.format_desc, r) {
                         None
                     } else {
                         Some(soft::BlitCommand::CopyImageToBuffer {
@@ -3605,7 +3957,7 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
                 self.copy_buffer(src_buffer, dst, regions.into_iter().map(|region| {
                     let r = region.borrow();
                     com::BufferCopy {
-                        src: src.byte_offset(r.image_offset),
+                        src: src.byte_offset(r.image_layers.level, r.image_offset),
                         dst: r.buffer_offset,
                         size: src.byte_extent(r.image_extent),
                     }
@@ -3623,6 +3975,16 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
             return
         }
 
+        if instances.start != 0 && self.shared.disabilities.no_base_vertex_instance {
+            let command = soft::RenderCommand::Draw {
+                primitive_type: self.state.primitive_type,
+                vertices,
+                instances,
+            };
+            self.issue_with_base(0, command);
+            return
+        }
+
         let command = soft::RenderCommand::Draw {
             primitive_type: self.state.primitive_type,
             vertices,
@@ -3645,6 +4007,20 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
             return
         }
 
+        self.state.check_primitive_restart();
+
+        if (base_vertex != 0 || instances.start != 0) && self.shared.disabilities.no_base_vertex_instance {
+            let command = soft::RenderCommand::DrawIndexed {
+                primitive_type: self.state.primitive_type,
+                index: self.state.index_buffer.expect("must bind index buffer"),
+                indices,
+                base_vertex: 0,
+                instances,
+            };
+            self.issue_with_base(base_vertex, command);
+            return
+        }
+
         let command = soft::RenderCommand::DrawIndexed {
             primitive_type: self.state.primitive_type,
             index: self.state.index_buffer.expect("must bind index buffer"),
@@ -3673,7 +4049,7 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
             .map(|i| soft::RenderCommand::DrawIndirect {
                 primitive_type: self.state.primitive_type,
                 buffer: AsNative::from(buffer.raw.as_ref()),
-                offset: offset + (i * stride) as buffer::Offset,
+                offset: buffer.range.start + offset + (i * stride) as buffer::Offset,
             });
 
         self.inner
@@ -3692,13 +4068,14 @@ impl com::RawCommandBuffer<Backend> for CommandBuffer {
     ) {
         assert_eq!(offset % WORD_ALIGNMENT, 0);
         assert_eq!(stride % WORD_ALIGNMENT as u32, 0);
+        self.state.check_primitive_restart();
 
         let commands = (0 .. count)
             .map(|i| soft::RenderCommand::DrawIndexedIndirect {
                 primitive_type: self.state.primitive_type,
                 index: self.state.index_buffer.expect("must bind index buffer"),
                 buffer: AsNative::from(buffer.raw.as_ref()),
-                offset: offset + (i * stride) as buffer::Offset,
+                offset: buffer.range.start + offset + (i * stride) as buffer::Offset,
             });
 
         self.inner
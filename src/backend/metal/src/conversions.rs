@@ -1,9 +1,12 @@
-use PrivateCapabilities;
+use {PrivateCapabilities, MAX_SAMPLER_ANISOTROPY};
 
 use hal::{pass, image, pso, IndexType};
-use hal::format::{Format, Swizzle, Properties};
+use hal::format::{Component, Format, Swizzle, Properties};
 use hal::pso::{Comparison, StencilOp};
 use metal::*;
+use spirv_cross::msl;
+
+use std::ops::Range;
 
 impl PrivateCapabilities {
     pub fn map_format(&self, format: Format) -> Option<MTLPixelFormat> {
@@ -19,7 +22,7 @@ impl PrivateCapabilities {
             f::Rgba8Srgb      if self.format_min_srgb_channels <= 4 => RGBA8Unorm_sRGB,
             f::Bgra8Srgb      if self.format_min_srgb_channels <= 4 => BGRA8Unorm_sRGB,
             f::D24UnormS8Uint if self.format_depth24_stencil8 => Depth24Unorm_Stencil8,
-            f::D32FloatS8Uint if self.format_depth32_stencil8_filter => Depth32Float_Stencil8,
+            f::D32FloatS8Uint if self.format_depth32_stencil8_filter || self.format_depth32_stencil8_none => Depth32Float_Stencil8,
             f::R8Unorm           => R8Unorm,
             f::R8Inorm           => R8Snorm,
             f::R8Uint            => R8Uint,
@@ -141,21 +144,28 @@ impl PrivateCapabilities {
         })
     }
 
-    pub fn map_format_with_swizzle(&self, format: Format, swizzle: Swizzle) -> Option<MTLPixelFormat> {
+    /// Returns the pixel format to use for the view, together with the swizzle
+    /// that still needs to be applied natively (`Swizzle::NO` if none).
+    pub fn map_format_with_swizzle(&self, format: Format, swizzle: Swizzle) -> Option<(MTLPixelFormat, Swizzle)> {
         use hal::format::{Component::*, Format::*};
         use metal::MTLPixelFormat as Pf;
         match (format, swizzle) {
-            (R8Unorm, Swizzle(Zero, Zero, Zero, R)) => Some(Pf::A8Unorm),
-            (Rgba8Unorm, Swizzle(B, G, R, A)) => Some(Pf::BGRA8Unorm),
-            (Bgra8Unorm, Swizzle(B, G, R, A)) => Some(Pf::RGBA8Unorm),
-            (Bgra8Srgb, Swizzle(B, G, R, A)) => Some(Pf::RGBA8Unorm_sRGB),
-            (B5g6r5Unorm, Swizzle(B, G, R, A)) if self.format_b5 => Some(Pf::B5G6R5Unorm),
+            (R8Unorm, Swizzle(Zero, Zero, Zero, R)) => Some((Pf::A8Unorm, Swizzle::NO)),
+            (Rgba8Unorm, Swizzle(B, G, R, A)) => Some((Pf::BGRA8Unorm, Swizzle::NO)),
+            (Bgra8Unorm, Swizzle(B, G, R, A)) => Some((Pf::RGBA8Unorm, Swizzle::NO)),
+            (Bgra8Srgb, Swizzle(B, G, R, A)) => Some((Pf::RGBA8Unorm_sRGB, Swizzle::NO)),
+            (B5g6r5Unorm, Swizzle(B, G, R, A)) if self.format_b5 => Some((Pf::B5G6R5Unorm, Swizzle::NO)),
             _ => {
                 let bits = format.base_format().0.describe_bits();
-                if swizzle != Swizzle::NO && !(bits.alpha == 0 && swizzle == Swizzle(R, G, B, One)) {
+                let remaining = if swizzle == Swizzle::NO || (bits.alpha == 0 && swizzle == Swizzle(R, G, B, One)) {
+                    Swizzle::NO
+                } else if self.texture_swizzle {
+                    swizzle
+                } else {
                     error!("Unsupported swizzle {:?} for format {:?}", swizzle, format);
-                }
-                self.map_format(format)
+                    Swizzle::NO
+                };
+                self.map_format(format).map(|f| (f, remaining))
             }
         }
     }
@@ -928,6 +938,10 @@ impl PrivateCapabilities {
                     | If::SAMPLED_LINEAR,
                 .. Properties::default()
             },
+            Depth32Float_Stencil8 if self.format_depth32_stencil8_none => Properties {
+                optimal_tiling: depth_if,
+                .. Properties::default()
+            },
             BGR10A2Unorm if self.format_bgr10a2_all => Properties {
                 optimal_tiling: color_if
                     | If::SAMPLED_LINEAR
@@ -967,6 +981,16 @@ pub fn map_store_operation(operation: pass::AttachmentStoreOp) -> MTLStoreAction
     }
 }
 
+/// Store action for a color attachment that is also resolved at the end of the subpass.
+pub fn map_resolve_store_operation(operation: pass::AttachmentStoreOp) -> MTLStoreAction {
+    use self::pass::AttachmentStoreOp::*;
+
+    match operation {
+        Store => MTLStoreAction::StoreAndMultisampleResolve,
+        DontCare => MTLStoreAction::MultisampleResolve,
+    }
+}
+
 pub fn map_write_mask(mask: pso::ColorMask) -> MTLColorWriteMask {
     let mut mtl_mask = MTLColorWriteMask::empty();
 
@@ -1169,6 +1193,24 @@ pub fn map_filter(filter: image::Filter) -> MTLSamplerMinMagFilter {
     }
 }
 
+pub fn map_msl_version(version: MTLLanguageVersion) -> msl::Version {
+    match version {
+        MTLLanguageVersion::V1_0 => msl::Version::V1_0,
+        MTLLanguageVersion::V1_1 => msl::Version::V1_1,
+        MTLLanguageVersion::V1_2 => msl::Version::V1_2,
+        MTLLanguageVersion::V2_0 => msl::Version::V2_0,
+    }
+}
+
+pub fn map_anisotropy(aniso: u8) -> u8 {
+    if aniso > MAX_SAMPLER_ANISOTROPY {
+        warn!("Anisotropy {} is clamped to {}", aniso, MAX_SAMPLER_ANISOTROPY);
+        MAX_SAMPLER_ANISOTROPY
+    } else {
+        aniso
+    }
+}
+
 pub fn map_wrap_mode(wrap: image::WrapMode) -> MTLSamplerAddressMode {
     match wrap {
         image::WrapMode::Tile => MTLSamplerAddressMode::Repeat,
@@ -1178,6 +1220,27 @@ pub fn map_wrap_mode(wrap: image::WrapMode) -> MTLSamplerAddressMode {
     }
 }
 
+/// Metal only has the three preset border colors, so anything else
+/// is approximated with the closest one.
+pub fn map_border_color(color: image::PackedColor) -> MTLSamplerBorderColor {
+    match color.0 {
+        0x00000000 => MTLSamplerBorderColor::TransparentBlack,
+        0xFF000000 => MTLSamplerBorderColor::OpaqueBlack,
+        0xFFFFFFFF => MTLSamplerBorderColor::OpaqueWhite,
+        other => {
+            debug!("Border color 0x{:08X} is approximated by the nearest preset", other);
+            let c: [f32; 4] = color.into();
+            if c[3] < 0.5 {
+                MTLSamplerBorderColor::TransparentBlack
+            } else if c[0] + c[1] + c[2] < 1.5 {
+                MTLSamplerBorderColor::OpaqueBlack
+            } else {
+                MTLSamplerBorderColor::OpaqueWhite
+            }
+        }
+    }
+}
+
 pub fn map_extent(extent: image::Extent) -> MTLSize {
     MTLSize {
         width: extent.width as _,
@@ -1207,6 +1270,56 @@ pub fn map_stencil_op(op: StencilOp) -> MTLStencilOperation {
     }
 }
 
+/// Mirrors `MTLTextureSwizzleChannels`, which is not exposed by `metal` yet.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MTLTextureSwizzleChannels {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub alpha: u8,
+}
+
+pub fn map_swizzle(swizzle: Swizzle) -> MTLTextureSwizzleChannels {
+    fn map_component(component: Component) -> u8 {
+        // values of `MTLTextureSwizzle`
+        match component {
+            Component::Zero => 0,
+            Component::One => 1,
+            Component::R => 2,
+            Component::G => 3,
+            Component::B => 4,
+            Component::A => 5,
+        }
+    }
+    MTLTextureSwizzleChannels {
+        red: map_component(swizzle.0),
+        green: map_component(swizzle.1),
+        blue: map_component(swizzle.2),
+        alpha: map_component(swizzle.3),
+    }
+}
+
+pub fn map_viewport(rect: &pso::Rect, depth: &Range<f32>) -> MTLViewport {
+    MTLViewport {
+        originX: rect.x as _,
+        originY: rect.y as _,
+        width: rect.w as _,
+        height: rect.h as _,
+        znear: depth.start as _,
+        zfar: depth.end as _,
+    }
+}
+
+pub fn map_scissor(rect: &pso::Rect) -> MTLScissorRect {
+    MTLScissorRect {
+        x: rect.x as _,
+        y: rect.y as _,
+        width: rect.w as _,
+        height: rect.h as _,
+    }
+}
+
 pub fn map_winding(face: pso::FrontFace) -> MTLWinding {
     match face {
         pso::FrontFace::Clockwise => MTLWinding::Clockwise,
@@ -1222,3 +1335,46 @@ pub fn map_cull_face(face: pso::Face) -> Option<MTLCullMode> {
         _ => None,
     }
 }
+
+#[test]
+fn test_map_swizzle_broadcast() {
+    use hal::format::Component::*;
+    assert_eq!(
+        map_swizzle(Swizzle(R, R, R, One)),
+        MTLTextureSwizzleChannels { red: 2, green: 2, blue: 2, alpha: 1 },
+    );
+}
+
+#[test]
+fn test_map_resolve_store_operation() {
+    assert_eq!(
+        map_resolve_store_operation(pass::AttachmentStoreOp::Store) as u64,
+        MTLStoreAction::StoreAndMultisampleResolve as u64,
+    );
+    assert_eq!(
+        map_resolve_store_operation(pass::AttachmentStoreOp::DontCare) as u64,
+        MTLStoreAction::MultisampleResolve as u64,
+    );
+}
+
+#[test]
+fn test_map_border_color_nearest() {
+    let map = |c: [f32; 4]| map_border_color(c.into()) as u64;
+    assert_eq!(map([0.0, 0.0, 0.0, 1.0]), MTLSamplerBorderColor::OpaqueBlack as u64);
+    assert_eq!(map([0.2, 0.3, 0.1, 0.9]), MTLSamplerBorderColor::OpaqueBlack as u64);
+    assert_eq!(map([0.9, 0.8, 0.7, 1.0]), MTLSamplerBorderColor::OpaqueWhite as u64);
+    assert_eq!(map([1.0, 1.0, 1.0, 0.1]), MTLSamplerBorderColor::TransparentBlack as u64);
+}
+
+#[test]
+fn test_map_anisotropy_clamped() {
+    assert_eq!(map_anisotropy(4), 4);
+    assert_eq!(map_anisotropy(32), 16);
+}
+
+#[test]
+fn test_map_vertex_format_unsupported() {
+    assert!(map_vertex_format(Format::Rgba8Unorm).is_some());
+    assert!(map_vertex_format(Format::Bc1RgbaUnorm).is_none());
+    assert!(map_vertex_format(Format::D32Float).is_none());
+}
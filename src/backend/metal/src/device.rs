@@ -1,6 +1,6 @@
 use {
     AsNative, Backend, PrivateCapabilities, QueueFamily, ResourceIndex, OnlineRecording,
-    Shared, Surface, Swapchain, VisibilityShared,
+    Shared, Surface, Swapchain, VisibilityShared, MAX_EXPOSED_QUEUES, MAX_SAMPLER_ANISOTROPY,
     validate_line_width,
 };
 use {conversions as conv, command, native as n};
@@ -8,16 +8,18 @@ use internal::{Channel, FastStorageMap};
 use native;
 use range_alloc::RangeAllocator;
 
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::collections::hash_map::Entry;
 use std::ops::Range;
 use std::path::Path;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{cmp, iter, mem, ptr, slice, thread, time};
 
 use hal::{self, error, image, pass, format, mapping, memory, buffer, pso, query};
+use hal::backend::FastHashMap;
 use hal::device::{BindError, OutOfMemory, FramebufferError, ShaderError};
 use hal::memory::Properties;
 use hal::pool::CommandPoolCreateFlags;
@@ -29,7 +31,7 @@ use foreign_types::ForeignType;
 use metal::{self,
     MTLFeatureSet, MTLLanguageVersion, MTLArgumentAccess, MTLDataType, MTLPrimitiveType, MTLPrimitiveTopologyClass,
     MTLCPUCacheMode, MTLStorageMode, MTLResourceOptions,
-    MTLVertexStepFunction, MTLSamplerBorderColor, MTLSamplerMipFilter, MTLTextureType,
+    MTLVertexStepFunction, MTLSamplerMipFilter, MTLTextureType, MTLSize,
     CaptureManager
 };
 use objc::rc::autoreleasepool;
@@ -43,8 +45,15 @@ const RESOURCE_HEAP_SUPPORT: &[MTLFeatureSet] = &[
     MTLFeatureSet::iOS_GPUFamily2_v3,
     MTLFeatureSet::iOS_GPUFamily3_v2,
     MTLFeatureSet::tvOS_GPUFamily1_v2,
+    MTLFeatureSet::macOS_GPUFamily1_v3,
 ];
 
+/// `MTLHazardTrackingModeTracked`, not exposed by `metal` yet.
+const MTL_HAZARD_TRACKING_MODE_TRACKED: NSUInteger = 2;
+
+/// Maximum vertex descriptor layout stride, the same for all the feature sets.
+const MAX_VERTEX_BUFFER_STRIDE: pso::ElemStride = 2048;
+
 const ARGUMENT_BUFFER_SUPPORT: &[MTLFeatureSet] = &[
     MTLFeatureSet::iOS_GPUFamily1_v4,
     MTLFeatureSet::tvOS_GPUFamily1_v3,
@@ -164,9 +173,40 @@ const BGR10A2_ALL: &[MTLFeatureSet] = &[
     MTLFeatureSet::tvOS_GPUFamily2_v1,
 ];
 
-const BASE_INSTANCE_SUPPORT: &[MTLFeatureSet] = &[
+const TEXTURE_SIZE_16K_SUPPORT: &[MTLFeatureSet] = &[
+    MTLFeatureSet::iOS_GPUFamily3_v1,
+    MTLFeatureSet::iOS_GPUFamily3_v2,
+    MTLFeatureSet::iOS_GPUFamily3_v3,
+    MTLFeatureSet::iOS_GPUFamily4_v1,
+    MTLFeatureSet::tvOS_GPUFamily2_v1,
+    MTLFeatureSet::macOS_GPUFamily1_v1,
+    MTLFeatureSet::macOS_GPUFamily1_v2,
+    MTLFeatureSet::macOS_GPUFamily1_v3,
+];
+
+const TEXTURE_SIZE_8K_SUPPORT: &[MTLFeatureSet] = &[
+    MTLFeatureSet::iOS_GPUFamily1_v2,
+    MTLFeatureSet::iOS_GPUFamily2_v2,
+    MTLFeatureSet::iOS_GPUFamily1_v3,
+    MTLFeatureSet::iOS_GPUFamily2_v3,
+    MTLFeatureSet::iOS_GPUFamily1_v4,
+    MTLFeatureSet::iOS_GPUFamily2_v4,
+    MTLFeatureSet::tvOS_GPUFamily1_v1,
+    MTLFeatureSet::tvOS_GPUFamily1_v2,
+    MTLFeatureSet::tvOS_GPUFamily1_v3,
+];
+
+pub(crate) const BASE_INSTANCE_SUPPORT: &[MTLFeatureSet] = &[
     MTLFeatureSet::iOS_GPUFamily1_v4,
     MTLFeatureSet::iOS_GPUFamily3_v1,
+    MTLFeatureSet::tvOS_GPUFamily2_v1,
+    MTLFeatureSet::macOS_GPUFamily1_v1,
+];
+
+pub(crate) const STORE_AND_RESOLVE_SUPPORT: &[MTLFeatureSet] = &[
+    MTLFeatureSet::iOS_GPUFamily1_v3,
+    MTLFeatureSet::tvOS_GPUFamily1_v2,
+    MTLFeatureSet::macOS_GPUFamily1_v2,
 ];
 
 const DUAL_SOURCE_BLEND_SUPPORT: &[MTLFeatureSet] = &[
@@ -195,6 +235,45 @@ enum FunctionError {
     BadSpecialization,
 }
 
+/// Size in bytes of the scalar function constant types, `None` for the rest.
+fn scalar_data_type_size(ty: MTLDataType) -> Option<usize> {
+    match ty {
+        MTLDataType::Bool | MTLDataType::Char | MTLDataType::UChar => Some(1),
+        MTLDataType::Short | MTLDataType::UShort | MTLDataType::Half => Some(2),
+        MTLDataType::Int | MTLDataType::UInt | MTLDataType::Float => Some(4),
+        _ => None,
+    }
+}
+
+/// Checks that a framebuffer provides exactly one view per render pass attachment.
+fn check_framebuffer_attachments(
+    render_pass: &n::RenderPass, provided: usize
+) -> Result<(), FramebufferError> {
+    if provided != render_pass.attachments.len() {
+        error!("Framebuffer has {} attachments, but the render pass expects {}",
+            provided, render_pass.attachments.len());
+        return Err(FramebufferError);
+    }
+    Ok(())
+}
+
+/// Returns the data to pass to Metal for a specialization constant of the given type,
+/// or `None` if its size doesn't match the type.
+fn specialization_constant_value(ty: MTLDataType, data: &[u8]) -> Option<Cow<[u8]>> {
+    match (ty, data.len()) {
+        // HAL follows Vulkan and passes booleans as 4-byte `VkBool32` values,
+        // which are true if the `u32` is not zero
+        (MTLDataType::Bool, 4) => {
+            let value = data.iter().any(|&byte| byte != 0);
+            Some(Cow::Owned(vec![value as u8]))
+        }
+        (_, size) => match scalar_data_type_size(ty) {
+            Some(expected) if expected != size => None,
+            _ => Some(Cow::Borrowed(data)),
+        },
+    }
+}
+
 fn get_final_function(
     library: &metal::LibraryRef, entry: &str, specialization: pso::Specialization
 ) -> Result<metal::Function, FunctionError> {
@@ -232,9 +311,22 @@ fn get_final_function(
         };
         match specialization.constants.iter().find(|c| c.id as NSUInteger == index) {
             Some(c) => unsafe {
-                let ptr = &specialization.data[c.range.start as usize] as *const u8 as *const _;
                 let ty: MTLDataType = msg_send![object, type];
-                constants.set_constant_value_at_index(c.id as NSUInteger, ty, ptr);
+                if c.range.start > c.range.end || c.range.end as usize > specialization.data.len() {
+                    error!("Specialization constant id {} is out of the data range", index);
+                    return Err(FunctionError::BadSpecialization)
+                }
+                let data = &specialization.data[c.range.start as usize .. c.range.end as usize];
+                // Metal has no 64-bit function constants, so anything wider is rejected here
+                let value = match specialization_constant_value(ty, data) {
+                    Some(value) => value,
+                    None => {
+                        error!("Specialization constant id {} of type {:?} has {} bytes of data",
+                            index, ty, data.len());
+                        return Err(FunctionError::BadSpecialization)
+                    }
+                };
+                constants.set_constant_value_at_index(c.id as NSUInteger, ty, value.as_ptr() as *const _);
             }
             None if required != NO => {
                 //TODO: get name
@@ -271,14 +363,22 @@ pub struct Device {
     pub(crate) shared: Arc<Shared>,
     pub(crate) private_caps: PrivateCapabilities,
     memory_types: Vec<hal::MemoryType>,
+    /// Resource options of the memory types, used for heap size queries,
+    /// or empty if resource heaps are not used.
+    heap_resource_options: Vec<MTLResourceOptions>,
     pub online_recording: OnlineRecording,
 }
 unsafe impl Send for Device {}
 unsafe impl Sync for Device {}
 
+/// Number of open logical devices, used to start and stop
+/// the automatic capture exactly once.
+static OPEN_DEVICE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 impl Drop for Device {
     fn drop(&mut self) {
-        if cfg!(feature = "auto-capture") {
+        let remaining = OPEN_DEVICE_COUNT.fetch_sub(1, Ordering::AcqRel) - 1;
+        if cfg!(feature = "auto-capture") && remaining == 0 {
             info!("Metal capture stop");
             let shared_capture_manager = CaptureManager::shared();
             if let Some(default_capture_scope) = shared_capture_manager.default_capture_scope() {
@@ -332,6 +432,16 @@ impl PhysicalDevice {
         features_sets.iter().cloned().any(|x| raw.supports_feature_set(x))
     }
 
+    fn sample_count_mask(raw: &metal::DeviceRef) -> u8 {
+        (0 .. 7)
+            .filter(|&shift| {
+                let count = 1 as NSUInteger << shift;
+                let supported: BOOL = unsafe { msg_send![raw, supportsTextureSampleCount: count] };
+                supported != NO
+            })
+            .fold(0, |mask, shift| mask | 1 << shift)
+    }
+
     pub(crate) fn new(shared: Arc<Shared>) -> Self {
         let device = shared.device.lock();
 
@@ -344,7 +454,7 @@ impl PhysicalDevice {
         let minor = version.minor as u32;
         let os_is_mac = device.supports_feature_set(MTLFeatureSet::macOS_GPUFamily1_v1);
 
-        let private_caps = {
+        let mut private_caps = {
             PrivateCapabilities {
                 os_is_mac,
                 os_version: (major as u32, minor as u32),
@@ -367,9 +477,27 @@ impl PhysicalDevice {
                 } else {
                     MTLLanguageVersion::V1_0
                 },
-                exposed_queues: 1,
-                resource_heaps: Self::supports_any(&device, RESOURCE_HEAP_SUPPORT),
-                argument_buffers: Self::supports_any(&device, ARGUMENT_BUFFER_SUPPORT) && false, //TODO
+                exposed_queues: MAX_EXPOSED_QUEUES,
+                // Heap resources are not hazard tracked unless the heap is configured so,
+                // which is only possible since macOS 10.15 and iOS 13.
+                resource_heaps: Self::supports_any(&device, RESOURCE_HEAP_SUPPORT) && if os_is_mac {
+                    PrivateCapabilities::version_at_least(major, minor, 10, 15)
+                } else {
+                    PrivateCapabilities::version_at_least(major, minor, 13, 0)
+                },
+                // Note: the shaders need to be compiled with argument buffer bindings
+                argument_buffers: cfg!(feature = "argument-buffers") &&
+                    Self::supports_any(&device, ARGUMENT_BUFFER_SUPPORT),
+                events: if os_is_mac {
+                    PrivateCapabilities::version_at_least(major, minor, 10, 14)
+                } else {
+                    PrivateCapabilities::version_at_least(major, minor, 12, 0)
+                },
+                texture_swizzle: if os_is_mac {
+                    PrivateCapabilities::version_at_least(major, minor, 10, 15)
+                } else {
+                    PrivateCapabilities::version_at_least(major, minor, 13, 0)
+                },
                 shared_textures: !os_is_mac,
                 base_instance: Self::supports_any(&device, BASE_INSTANCE_SUPPORT),
                 dual_source_blending: Self::supports_any(&device, DUAL_SOURCE_BLEND_SUPPORT),
@@ -425,14 +553,52 @@ impl PhysicalDevice {
                 max_textures_per_stage: if os_is_mac {128} else {31},
                 max_samplers_per_stage: 16,
                 buffer_alignment: if os_is_mac {256} else {64},
+                max_texel_buffer_alignment: 0, // depends on `map_format`, computed below
+                constant_buffer_alignment: if os_is_mac {256} else {4},
                 max_buffer_size: if Self::supports_any(&device, &[MTLFeatureSet::macOS_GPUFamily1_v2, MTLFeatureSet::macOS_GPUFamily1_v3]) {
                     1 << 30 // 1GB on macOS 1.2 and up
                 } else {
                     1 << 28 // 256MB otherwise
                 },
-                max_texture_size: 4096, //TODO
+                max_private_heap_size: if os_is_mac && PrivateCapabilities::version_at_least(major, minor, 10, 12) {
+                    let raw: &metal::DeviceRef = &device;
+                    let size: u64 = unsafe { msg_send![raw, recommendedMaxWorkingSetSize] };
+                    size
+                } else {
+                    !0
+                },
+                max_texture_size: if Self::supports_any(&device, TEXTURE_SIZE_16K_SUPPORT) {
+                    16384
+                } else if Self::supports_any(&device, TEXTURE_SIZE_8K_SUPPORT) {
+                    8192
+                } else {
+                    4096
+                },
+                max_texture_3d_size: 2048,
+                max_texture_layers: 2048,
+                sample_count_mask: Self::sample_count_mask(&device),
+                max_threads_per_group: {
+                    let raw: &metal::DeviceRef = &device;
+                    let size: MTLSize = unsafe { msg_send![raw, maxThreadsPerThreadgroup] };
+                    [size.width as u32, size.height as u32, size.depth as u32]
+                },
+                max_viewports: shared.max_viewports,
             }
         };
+        let max_texel_buffer_alignment = (1 .. format::NUM_FORMATS)
+            .filter_map(|i| {
+                let format: format::Format = unsafe { mem::transmute(i as u32) };
+                let desc = format.surface_desc();
+                if desc.aspects.contains(format::Aspects::COLOR) && !desc.is_compressed() {
+                    private_caps.map_format(format)
+                } else {
+                    None
+                }
+            })
+            .map(|mtl_format| private_caps.linear_texture_alignment(&device, mtl_format))
+            .max()
+            .unwrap_or(private_caps.buffer_alignment);
+        private_caps.max_texel_buffer_alignment = max_texel_buffer_alignment;
 
         let memory_types = if os_is_mac {
             vec![
@@ -477,14 +643,20 @@ impl hal::PhysicalDevice<Backend> for PhysicalDevice {
     fn open(
         &self, families: &[(&QueueFamily, &[hal::QueuePriority])],
     ) -> Result<hal::Gpu<Backend>, error::DeviceCreationError> {
-        // TODO: Handle opening a physical device multiple times
         assert_eq!(families.len(), 1);
-        assert_eq!(families[0].1.len(), 1);
-        let family = *families[0].0;
-        let device = self.shared.device.lock();
+        let (&family, priorities) = families[0];
+        if priorities.is_empty() || priorities.len() > MAX_EXPOSED_QUEUES {
+            error!("Unable to open {} queues, the family supports up to {}", priorities.len(), MAX_EXPOSED_QUEUES);
+            return Err(error::DeviceCreationError::InitializationFailed);
+        }
+        // Every logical device gets its own queue and service pipelines,
+        // so that opening the physical device again doesn't interfere.
+        let shared = Arc::new(Shared::new(self.shared.device.lock().clone()));
 
-        if cfg!(feature = "auto-capture") {
+        let is_first = OPEN_DEVICE_COUNT.fetch_add(1, Ordering::AcqRel) == 0;
+        if cfg!(feature = "auto-capture") && is_first {
             info!("Metal capture start");
+            let device = shared.device.lock();
             let shared_capture_manager = CaptureManager::shared();
             let default_capture_scope = shared_capture_manager.new_capture_scope_with_device(&*device);
             shared_capture_manager.set_default_capture_scope(default_capture_scope);
@@ -493,14 +665,29 @@ impl hal::PhysicalDevice<Backend> for PhysicalDevice {
         }
 
         let mut queue_group = hal::backend::RawQueueGroup::new(family);
-        for _ in 0 .. self.private_caps.exposed_queues {
-            queue_group.add_queue(command::CommandQueue::new(self.shared.clone()));
+        for _ in priorities {
+            queue_group.add_queue(command::CommandQueue::new(shared.clone()));
         }
 
+        let heap_resource_options = if self.private_caps.resource_heaps {
+            (0 .. self.memory_types.len())
+                .map(|i| {
+                    let (storage, cache) = MemoryTypes::describe(i);
+                    conv::resource_options_from_storage_and_cache(storage, cache)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
         let device = Device {
-            shared: self.shared.clone(),
-            private_caps: self.private_caps.clone(),
+            shared,
+            private_caps: PrivateCapabilities {
+                exposed_queues: priorities.len(),
+                .. self.private_caps.clone()
+            },
             memory_types: self.memory_types.clone(),
+            heap_resource_options,
             online_recording: OnlineRecording::default(),
         };
 
@@ -547,20 +734,33 @@ impl hal::PhysicalDevice<Backend> for PhysicalDevice {
             // Can't create 2D/2DArray views of 3D textures
             return None;
         }
-        //TODO: actually query this data
-        let max_dimension = 4096u32;
+        let max_dimension = if dimensions == 3 {
+            self.private_caps.max_texture_3d_size
+        } else {
+            self.private_caps.max_texture_size
+        } as u32;
         let max_extent = image::Extent {
             width: max_dimension,
             height: if dimensions >= 2 { max_dimension } else { 1 },
             depth: if dimensions >= 3 { max_dimension } else { 1 },
         };
+        // Only 2D non-cube textures can be multisampled
+        let sample_count_mask = if dimensions == 2 &&
+            !view_caps.contains(image::ViewCapabilities::KIND_CUBE) &&
+            !usage.contains(image::Usage::STORAGE) &&
+            tiling == image::Tiling::Optimal
+        {
+            self.private_caps.sample_count_mask
+        } else {
+            0x1
+        };
 
         self.private_caps.map_format(format).map(|_| image::FormatProperties {
             max_extent,
-            max_levels: if dimensions == 1 { 1 } else { 12 },
+            max_levels: if dimensions == 1 { 1 } else { (32 - max_dimension.leading_zeros()) as _ },
             // 3D images enforce a single layer
-            max_layers: if dimensions == 3 { 1 } else { 2048 },
-            sample_count_mask: 0x1,
+            max_layers: if dimensions == 3 { 1 } else { self.private_caps.max_texture_layers as _ },
+            sample_count_mask,
             //TODO: buffers and textures have separate limits
             // Max buffer size is determined by feature set
             // Max texture size does not appear to be documented publicly
@@ -571,7 +771,7 @@ impl hal::PhysicalDevice<Backend> for PhysicalDevice {
     fn memory_properties(&self) -> hal::MemoryProperties {
         hal::MemoryProperties {
             memory_heaps: vec![
-                !0, //TODO: private memory limits
+                self.private_caps.max_private_heap_size,
                 self.private_caps.max_buffer_size,
             ],
             memory_types: self.memory_types.to_vec(),
@@ -597,34 +797,35 @@ impl hal::PhysicalDevice<Backend> for PhysicalDevice {
             max_texel_elements: (self.private_caps.max_texture_size * self.private_caps.max_texture_size) as usize,
             max_patch_size: 0, // No tessellation
 
-            // Note: The maximum number of supported viewports and scissor rectangles varies by device.
-            // TODO: read from Metal Feature Sets.
-            max_viewports: 1,
+            max_viewports: self.private_caps.max_viewports,
 
             min_buffer_copy_offset_alignment: self.private_caps.buffer_alignment,
             min_buffer_copy_pitch_alignment: 4,
-            min_texel_buffer_offset_alignment: self.private_caps.buffer_alignment,
-            min_uniform_buffer_offset_alignment: self.private_caps.buffer_alignment,
+            min_texel_buffer_offset_alignment: self.private_caps.max_texel_buffer_alignment,
+            min_uniform_buffer_offset_alignment: self.private_caps.constant_buffer_alignment,
             min_storage_buffer_offset_alignment: self.private_caps.buffer_alignment,
 
-            max_compute_group_count: [16; 3], // TODO
-            max_compute_group_size: [64; 3], // TODO
+            // Metal doesn't limit the grid size, so we report the minimum required by Vulkan
+            max_compute_group_count: [0xFFFF; 3],
+            max_compute_group_size: self.private_caps.max_threads_per_group,
 
             max_vertex_input_attributes: 31,
             max_vertex_input_bindings: 31,
-            max_vertex_input_attribute_offset: 255, // TODO
-            max_vertex_input_binding_stride: 256, // TODO
+            max_vertex_input_attribute_offset: MAX_VERTEX_BUFFER_STRIDE as usize - 1,
+            max_vertex_input_binding_stride: MAX_VERTEX_BUFFER_STRIDE as usize,
             max_vertex_output_components: 16, // TODO
 
-            framebuffer_color_samples_count: 0b101, // TODO
-            framebuffer_depth_samples_count: 0b101, // TODO
-            framebuffer_stencil_samples_count: 0b101, // TODO
+            // Metal reports sample count support per device, regardless of the attachment type
+            framebuffer_color_samples_count: self.private_caps.sample_count_mask,
+            framebuffer_depth_samples_count: self.private_caps.sample_count_mask,
+            framebuffer_stencil_samples_count: self.private_caps.sample_count_mask,
             max_color_attachments: 1, // TODO
 
             // Note: we issue Metal buffer-to-buffer copies on memory flush/invalidate,
             // and those need to operate on sizes being multiples of 4.
-            non_coherent_atom_size: 4,
-            max_sampler_anisotropy: 16.,
+            // The flushed and invalidated ranges are expanded to this size if needed.
+            non_coherent_atom_size: n::NON_COHERENT_ATOM_SIZE as usize,
+            max_sampler_anisotropy: MAX_SAMPLER_ANISOTROPY as f32,
         }
     }
 }
@@ -641,6 +842,38 @@ impl LanguageVersion {
 }
 
 impl Device {
+    /// Memory types exposed by this device. Managed ones are only available on macOS.
+    fn exposed_memory_types(&self) -> MemoryTypes {
+        MemoryTypes::from_bits_truncate((1 << self.memory_types.len()) - 1)
+    }
+
+    /// Resolves the range of a buffer descriptor, reporting an error if it's out of bounds.
+    /// Metal binds buffers by offset only, so the end of the range can't be enforced by the encoder.
+    fn resolve_descriptor_range(
+        buf: &n::Buffer, range: &Range<Option<buffer::Offset>>
+    ) -> Option<Range<buffer::Offset>> {
+        let sub = buf.resolve_range(range);
+        if sub.is_none() {
+            error!("Descriptor range {:?} is out of bounds of the buffer of size {}",
+                range, buf.range.end - buf.range.start);
+        }
+        sub
+    }
+
+    /// Lets the native heap reuse the memory of a resource that was sub-allocated from it.
+    /// Native heaps are created with tracked hazards, so Metal still orders the accesses
+    /// of the destroyed resource before the ones of any resource aliasing its memory.
+    fn release_heap_resource(&self, resource: &metal::ResourceRef) {
+        if !self.private_caps.resource_heaps {
+            return
+        }
+        let heap: *mut Object = unsafe { msg_send![resource, heap] };
+        if !heap.is_null() {
+            debug!("\tresource {:p} becomes aliasable", resource);
+            let () = unsafe { msg_send![resource, makeAliasable] };
+        }
+    }
+
     fn _is_heap_coherent(&self, heap: &n::MemoryHeap) -> bool {
         match *heap {
             n::MemoryHeap::Private => false,
@@ -685,12 +918,7 @@ impl Device {
             .map_err(|e| ShaderError::CompilationFailed(e.into()))
     }
 
-    fn compile_shader_library(
-        device: &Mutex<metal::Device>,
-        raw_data: &[u8],
-        compiler_options: &msl::CompilerOptions,
-        msl_version: MTLLanguageVersion,
-    ) -> Result<n::ModuleInfo, ShaderError> {
+    fn parse_shader(raw_data: &[u8]) -> Result<spirv::Ast<msl::Target>, ShaderError> {
         // spec requires "codeSize must be a multiple of 4"
         assert_eq!(raw_data.len() & 3, 0);
 
@@ -701,10 +929,26 @@ impl Device {
             )
         });
 
-        // now parse again using the new overrides
-        let mut ast = spirv::Ast::<msl::Target>::parse(&module)
-            .map_err(gen_parse_error)?;
+        spirv::Ast::<msl::Target>::parse(&module)
+            .map_err(gen_parse_error)
+    }
+
+    fn compile_shader_library(
+        device: &Mutex<metal::Device>,
+        raw_data: &[u8],
+        compiler_options: &msl::CompilerOptions,
+        msl_version: MTLLanguageVersion,
+    ) -> Result<n::ModuleInfo, ShaderError> {
+        let mut ast = Self::parse_shader(raw_data)?;
+        Self::compile_shader_ast(device, &mut ast, compiler_options, msl_version)
+    }
 
+    fn compile_shader_ast(
+        device: &Mutex<metal::Device>,
+        ast: &mut spirv::Ast<msl::Target>,
+        compiler_options: &msl::CompilerOptions,
+        msl_version: MTLLanguageVersion,
+    ) -> Result<n::ModuleInfo, ShaderError> {
         ast.set_compiler_options(compiler_options)
             .map_err(|err| {
                 ShaderError::CompilationFailed(match err {
@@ -780,28 +1024,47 @@ impl Device {
 
         let info = match *ep.module {
             n::ShaderModule::Compiled(ref info) => info,
-            n::ShaderModule::Raw(ref data) => {
+            n::ShaderModule::Raw { ref data, ref ast, ref compiled } => {
                 let compiler_options = match primitive_class {
                     MTLPrimitiveTopologyClass::Point => &layout.shader_compiler_options_point,
                     _ => &layout.shader_compiler_options,
                 };
+                let compile = || -> Result<n::ModuleInfo, pso::CreationError> {
+                    // only recompile the module if it hasn't been used with these options yet
+                    let cached = compiled.lock().get(compiler_options).cloned();
+                    match cached {
+                        Some(info) => Ok(info),
+                        None => {
+                            let info = Self::compile_shader_ast(device, &mut *ast.lock(), compiler_options, msl_version)
+                                .map_err(|e| {
+                                    error!("Error compiling the shader {:?}", e);
+                                    pso::CreationError::Shader(e)
+                                })?;
+                            compiled.lock().insert(compiler_options.clone(), info.clone());
+                            Ok(info)
+                        }
+                    }
+                };
                 match pipeline_cache {
                     Some(cache) => {
                         module_map = cache.modules.get_or_create_with(compiler_options, || {
                             FastStorageMap::default()
                         });
-                        info_guard = module_map.get_or_create_with(data, || {
-                            Self::compile_shader_library(device, data, compiler_options, msl_version)
-                                .unwrap()
+                        // only compile on a cache miss
+                        let mut compile_error = None;
+                        module_map.prepare_maybe(data, || {
+                            compile()
+                                .map_err(|e| compile_error = Some(e))
+                                .ok()
                         });
+                        if let Some(e) = compile_error {
+                            return Err(e)
+                        }
+                        info_guard = module_map.get_or_create_with(data, || unreachable!());
                         &*info_guard
                     }
                     None => {
-                        info_owned = Self::compile_shader_library(device, data, compiler_options, msl_version)
-                            .map_err(|e| {
-                                error!("Error compiling the shader {:?}", e);
-                                pso::CreationError::Other
-                            })?;
+                        info_owned = compile()?;
                         &info_owned
                     }
                 }
@@ -828,7 +1091,7 @@ impl Device {
     }
 
     fn describe_argument(
-        ty: pso::DescriptorType, index: pso::DescriptorBinding, count: usize
+        ty: pso::DescriptorType, index: NSUInteger, count: usize
     ) -> metal::ArgumentDescriptor {
         let arg = metal::ArgumentDescriptor::new().to_owned();
         arg.set_array_length(count as _);
@@ -837,28 +1100,61 @@ impl Device {
             pso::DescriptorType::Sampler => {
                 arg.set_access(MTLArgumentAccess::ReadOnly);
                 arg.set_data_type(MTLDataType::Sampler);
-                arg.set_index(index as _);
+                arg.set_index(index);
             }
-            pso::DescriptorType::SampledImage => {
+            pso::DescriptorType::SampledImage |
+            pso::DescriptorType::UniformTexelBuffer |
+            pso::DescriptorType::InputAttachment => {
                 arg.set_access(MTLArgumentAccess::ReadOnly);
                 arg.set_data_type(MTLDataType::Texture);
-                arg.set_index(index as _);
+                arg.set_index(index);
+            }
+            pso::DescriptorType::StorageImage |
+            pso::DescriptorType::StorageTexelBuffer => {
+                arg.set_access(MTLArgumentAccess::ReadWrite);
+                arg.set_data_type(MTLDataType::Texture);
+                arg.set_index(index);
             }
             pso::DescriptorType::UniformBuffer => {
                 arg.set_access(MTLArgumentAccess::ReadOnly);
                 arg.set_data_type(MTLDataType::Struct);
-                arg.set_index(index as _);
+                arg.set_index(index);
             }
             pso::DescriptorType::StorageBuffer => {
                 arg.set_access(MTLArgumentAccess::ReadWrite);
                 arg.set_data_type(MTLDataType::Struct);
-                arg.set_index(index as _);
+                arg.set_index(index);
+            }
+            pso::DescriptorType::UniformBufferDynamic |
+            pso::DescriptorType::StorageBufferDynamic => {
+                unreachable!("Sets with dynamic buffers use the emulated layout")
+            }
+            pso::DescriptorType::CombinedImageSampler => {
+                unreachable!("Combined image samplers are split by `describe_arguments`")
             }
-            _ => unimplemented!()
         }
 
         arg
     }
+
+    /// Appends the argument descriptors for a range of descriptors starting at `index`.
+    /// Returns the number of argument indices occupied by the range.
+    fn describe_arguments(
+        ty: pso::DescriptorType, index: NSUInteger, count: usize,
+        arguments: &mut Vec<metal::ArgumentDescriptor>,
+    ) -> NSUInteger {
+        match ty {
+            pso::DescriptorType::CombinedImageSampler => {
+                arguments.push(Self::describe_argument(pso::DescriptorType::SampledImage, index, count));
+                arguments.push(Self::describe_argument(pso::DescriptorType::Sampler, index + count as NSUInteger, count));
+                2 * count as NSUInteger
+            }
+            _ => {
+                arguments.push(Self::describe_argument(ty, index, count));
+                count as NSUInteger
+            }
+        }
+    }
 }
 
 impl hal::Device<Backend> for Device {
@@ -877,7 +1173,7 @@ impl hal::Device<Backend> for Device {
         &self,
         attachments: IA,
         subpasses: IS,
-        _dependencies: ID,
+        dependencies: ID,
     ) -> n::RenderPass
     where
         IA: IntoIterator,
@@ -891,16 +1187,38 @@ impl hal::Device<Backend> for Device {
             .into_iter()
             .map(|at| at.borrow().clone())
             .collect();
+        // the load/store tracking below uses a 64-bit mask per attachment
+        assert!(attachments.len() <= 64, "Too many render pass attachments: {}", attachments.len());
 
         let mut subpasses: Vec<n::Subpass> = subpasses
             .into_iter()
-            .map(|sp| {
+            .enumerate()
+            .map(|(index, sp)| {
                 let sub = sp.borrow();
+                for &(id, _) in sub.colors.iter().chain(sub.resolves).chain(sub.depth_stencil).chain(sub.inputs) {
+                    assert!(id < attachments.len(),
+                        "Subpass {} references attachment {} out of {}", index, id, attachments.len());
+                }
+                for &(id, _) in sub.inputs {
+                    // Inputs are bound as regular textures, so they can't alias the subpass outputs.
+                    if sub.colors.iter().chain(sub.depth_stencil).any(|&(out, _)| out == id) {
+                        error!("Subpass {} reads attachment {} as input while also writing to it, \
+                            which is not supported", index, id);
+                    }
+                }
+                if !sub.resolves.is_empty() && sub.resolves.len() != sub.colors.len() {
+                    error!("Subpass {} has {} resolve attachments for {} color attachments",
+                        index, sub.resolves.len(), sub.colors.len());
+                }
                 n::Subpass {
                     colors: sub.colors
                         .iter()
                         .map(|&(id, _)| (id, n::SubpassOps::empty()))
                         .collect(),
+                    resolves: sub.resolves
+                        .iter()
+                        .map(|&(id, _)| id)
+                        .collect(),
                     depth_stencil: sub.depth_stencil
                         .map(|&(id, _)| (id, n::SubpassOps::empty())),
                     inputs: sub.inputs
@@ -949,6 +1267,19 @@ impl hal::Device<Backend> for Device {
             }
         }
 
+        // Every subpass is encoded with its own `MTLRenderCommandEncoder`, and all our resources
+        // are hazard-tracked, so Metal orders the accesses between subpasses (and with respect
+        // to the work outside of the pass) for us. What can't be enforced is a dependency of
+        // a subpass on itself: that would require a `textureBarrier` between draw calls.
+        for dep in dependencies {
+            let dep = dep.borrow();
+            if let (pass::SubpassRef::Pass(src), pass::SubpassRef::Pass(dst)) = (dep.passes.start, dep.passes.end) {
+                if src == dst {
+                    warn!("Self-dependency of subpass {} (accesses {:?}) is not enforced", src, dep.accesses);
+                }
+            }
+        }
+
         n::RenderPass {
             attachments,
             subpasses,
@@ -1034,7 +1365,7 @@ impl hal::Device<Backend> for Device {
                         }
                     }
                 }
-                n::DescriptorSetLayout::ArgumentBuffer(_, stage_flags) => {
+                n::DescriptorSetLayout::ArgumentBuffer { stage_flags, .. } => {
                     for &mut (stage_bit, stage, ref mut counters) in stage_infos.iter_mut() {
                         if !stage_flags.contains(stage_bit) {
                             continue
@@ -1105,12 +1436,7 @@ impl hal::Device<Backend> for Device {
         }
 
         let mut shader_compiler_options = msl::CompilerOptions::default();
-        shader_compiler_options.version = match self.private_caps.msl_version {
-            MTLLanguageVersion::V1_0 => msl::Version::V1_0,
-            MTLLanguageVersion::V1_1 => msl::Version::V1_1,
-            MTLLanguageVersion::V1_2 => msl::Version::V1_2,
-            MTLLanguageVersion::V2_0 => msl::Version::V2_0,
-        };
+        shader_compiler_options.version = conv::map_msl_version(self.private_caps.msl_version);
         shader_compiler_options.enable_point_size_builtin = false;
         shader_compiler_options.resolve_specialized_array_lengths = true;
         shader_compiler_options.vertex.invert_y = true;
@@ -1204,6 +1530,9 @@ impl hal::Device<Backend> for Device {
     ) -> Result<n::GraphicsPipeline, pso::CreationError> {
         debug!("create_graphics_pipeline {:?}", pipeline_desc);
         let pipeline = metal::RenderPipelineDescriptor::new();
+        if let Some(name) = pipeline_desc.name {
+            pipeline.set_label(name);
+        }
         let pipeline_layout = &pipeline_desc.layout;
         let (rp_attachments, subpass) = {
             let pass::Subpass { main_pass, index } = pipeline_desc.subpass;
@@ -1220,6 +1549,17 @@ impl hal::Device<Backend> for Device {
         };
         pipeline.set_input_primitive_topology(primitive_class);
 
+        // Metal natively restarts indexed strips at the all-ones value of the bound index type,
+        // on every GPU family, so there is no need to split draws. It can't be turned off though,
+        // and a restart value that doesn't match the index type is checked at draw time.
+        match (pipeline_desc.input_assembler.primitive, pipeline_desc.input_assembler.primitive_restart) {
+            (hal::Primitive::LineStrip, pso::PrimitiveRestart::Disabled) |
+            (hal::Primitive::TriangleStrip, pso::PrimitiveRestart::Disabled) => {
+                debug!("\tprimitive restart can't be disabled for {:?}", pipeline_desc.input_assembler.primitive);
+            }
+            _ => {}
+        }
+
         // Vertex shader
         let (vs_lib, vs_function, _, enable_rasterization) = self.load_shader(
             &pipeline_desc.shaders.vertex,
@@ -1313,6 +1653,12 @@ impl hal::Device<Backend> for Device {
                 .iter()
                 .find(|vb| vb.binding == binding)
                 .expect("no associated vertex buffer found");
+            if element.offset >= MAX_VERTEX_BUFFER_STRIDE {
+                let msg = format!("Attribute[{}] offset {} exceeds the limit of {}",
+                    location, element.offset, MAX_VERTEX_BUFFER_STRIDE - 1);
+                error!("{}", msg);
+                return Err(pso::CreationError::Shader(ShaderError::InterfaceMismatch(msg)));
+            }
             // handle wrapping offsets
             let elem_size = element.format.surface_desc().bits as pso::ElemOffset / 8;
             let (cut_offset, base_offset) = if original.stride == 0 || element.offset + elem_size <= original.stride {
@@ -1334,9 +1680,10 @@ impl hal::Device<Backend> for Device {
                 });
             let mtl_buffer_index = attribute_buffer_index as usize + relative_index;
             if mtl_buffer_index >= self.private_caps.max_buffers_per_stage as usize {
-                error!("Attribute offset {} exceeds the stride {}, and there is no room for replacement.",
-                    element.offset, original.stride);
-                return Err(pso::CreationError::Other);
+                let msg = format!("Attribute[{}] of binding {} at offset {} exceeds the stride {}, \
+                    and there is no buffer slot left for replacement", location, binding, element.offset, original.stride);
+                error!("{}", msg);
+                return Err(pso::CreationError::Shader(ShaderError::InterfaceMismatch(msg)));
             }
             trace!("\tAttribute[{}] is mapped to vertex buffer[{}] with binding {} and offsets {} + {}",
                 location, binding, mtl_buffer_index, base_offset, cut_offset);
@@ -1345,8 +1692,15 @@ impl hal::Device<Backend> for Device {
                 .attributes()
                 .object_at(location as usize)
                 .expect("too many vertex attributes");
-            let mtl_vertex_format = conv::map_vertex_format(element.format)
-                .expect("unsupported vertex format");
+            let mtl_vertex_format = match conv::map_vertex_format(element.format) {
+                Some(format) => format,
+                None => {
+                    let msg = format!("Attribute[{}] format {:?} is not supported as a vertex format",
+                        location, element.format);
+                    error!("{}", msg);
+                    return Err(pso::CreationError::Shader(ShaderError::InterfaceMismatch(msg)));
+                }
+            };
             mtl_attribute_desc.set_format(mtl_vertex_format);
             mtl_attribute_desc.set_buffer_index(mtl_buffer_index as _);
             mtl_attribute_desc.set_offset(cut_offset as _);
@@ -1362,6 +1716,12 @@ impl hal::Device<Backend> for Device {
                 error!("Stride ({}) must be a multiple of {}", vb.stride, STRIDE_GRANULARITY);
                 return Err(pso::CreationError::Other);
             }
+            if vb.stride > MAX_VERTEX_BUFFER_STRIDE {
+                let msg = format!("Stride ({}) of binding {} exceeds the limit of {}",
+                    vb.stride, vb.binding, MAX_VERTEX_BUFFER_STRIDE);
+                error!("{}", msg);
+                return Err(pso::CreationError::Shader(ShaderError::InterfaceMismatch(msg)));
+            }
             if vb.stride != 0 {
                 mtl_buffer_desc.set_stride(vb.stride as u64);
                 if vb.rate == 0 {
@@ -1418,6 +1778,7 @@ impl hal::Device<Backend> for Device {
                     fs_lib,
                     raw,
                     primitive_type,
+                    primitive_restart: pipeline_desc.input_assembler.primitive_restart,
                     attribute_buffer_index,
                     vs_pc_info: pipeline_desc.layout.push_constants.vs,
                     ps_pc_info: pipeline_desc.layout.push_constants.ps,
@@ -1441,6 +1802,9 @@ impl hal::Device<Backend> for Device {
     ) -> Result<n::ComputePipeline, pso::CreationError> {
         debug!("create_compute_pipeline {:?}", pipeline_desc);
         let pipeline = metal::ComputePipelineDescriptor::new();
+        if let Some(name) = pipeline_desc.name {
+            pipeline.set_label(name);
+        }
 
         let (cs_lib, cs_function, work_group_size, _) = self.load_shader(
             &pipeline_desc.shader,
@@ -1467,28 +1831,35 @@ impl hal::Device<Backend> for Device {
     }
 
     fn create_framebuffer<I>(
-        &self, _render_pass: &n::RenderPass, attachments: I, extent: image::Extent
+        &self, render_pass: &n::RenderPass, attachments: I, extent: image::Extent
     ) -> Result<n::Framebuffer, FramebufferError>
     where
         I: IntoIterator,
         I::Item: Borrow<n::ImageView>
     {
+        let attachments: Vec<_> = attachments
+            .into_iter()
+            .map(|at| at.borrow().raw.clone())
+            .collect();
+        check_framebuffer_attachments(render_pass, attachments.len())?;
+
         Ok(n::Framebuffer {
             extent,
-            attachments: attachments
-                .into_iter()
-                .map(|at| at.borrow().raw.clone())
-                .collect(),
+            attachments,
         })
     }
 
     fn create_shader_module(&self, raw_data: &[u8]) -> Result<n::ShaderModule, ShaderError> {
-        //TODO: we can probably at least parse here and save the `Ast`
         let depends_on_pipeline_layout = true; //TODO: !self.private_caps.argument_buffers
         Ok(if depends_on_pipeline_layout {
-            n::ShaderModule::Raw(raw_data.to_vec())
+            n::ShaderModule::Raw {
+                data: raw_data.to_vec(),
+                ast: Mutex::new(Self::parse_shader(raw_data)?),
+                compiled: Mutex::new(FastHashMap::default()),
+            }
         } else {
             let mut options = msl::CompilerOptions::default();
+            options.version = conv::map_msl_version(self.private_caps.msl_version);
             options.enable_point_size_builtin = false;
             options.resolve_specialized_array_lengths = true;
             options.vertex.invert_y = true;
@@ -1511,7 +1882,7 @@ impl hal::Device<Backend> for Device {
         });
 
         if let image::Anisotropic::On(aniso) = info.anisotropic {
-            descriptor.set_max_anisotropy(aniso as _);
+            descriptor.set_max_anisotropy(conv::map_anisotropy(aniso) as _);
         }
 
         let (s, t, r) = info.wrap_mode;
@@ -1534,15 +1905,7 @@ impl hal::Device<Backend> for Device {
             descriptor.set_compare_function(conv::map_compare_function(fun));
         }
         if [r, s, t].iter().any(|&am| am == image::WrapMode::Border) {
-            descriptor.set_border_color(match info.border.0 {
-                0x00000000 => MTLSamplerBorderColor::TransparentBlack,
-                0x000000FF => MTLSamplerBorderColor::OpaqueBlack,
-                0xFFFFFFFF => MTLSamplerBorderColor::OpaqueWhite,
-                other => {
-                    error!("Border color 0x{:X} is not supported", other);
-                    MTLSamplerBorderColor::TransparentBlack
-                }
-            });
+            descriptor.set_border_color(conv::map_border_color(info.border));
         }
 
         n::Sampler(
@@ -1564,7 +1927,10 @@ impl hal::Device<Backend> for Device {
         let base_ptr = match memory.heap {
             n::MemoryHeap::Public(_, ref cpu_buffer) => cpu_buffer.contents() as *mut u8,
             n::MemoryHeap::Native(_) |
-            n::MemoryHeap::Private => panic!("Unable to map memory!"),
+            n::MemoryHeap::Private => {
+                error!("Unable to map memory that is not host visible");
+                return Err(mapping::Error::InvalidAccess);
+            }
         };
         Ok(unsafe { base_ptr.offset(range.start as _) })
     }
@@ -1582,11 +1948,10 @@ impl hal::Device<Backend> for Device {
         debug!("flush_mapped_memory_ranges");
         for item in iter {
             let (memory, ref generic_range) = *item.borrow();
-            let range = memory.resolve(generic_range);
+            let range = memory.resolve_aligned(generic_range);
             debug!("\trange {:?}", range);
 
             match memory.heap {
-                n::MemoryHeap::Native(_) => unimplemented!(),
                 n::MemoryHeap::Public(mt, ref cpu_buffer) if 1<<mt.0 != MemoryTypes::SHARED.bits() as usize => {
                     cpu_buffer.did_modify_range(NSRange {
                         location: range.start as _,
@@ -1594,7 +1959,11 @@ impl hal::Device<Backend> for Device {
                     });
                 }
                 n::MemoryHeap::Public(..) => continue,
-                n::MemoryHeap::Private => panic!("Can't map private memory!"),
+                n::MemoryHeap::Native(_) |
+                n::MemoryHeap::Private => {
+                    error!("Unable to flush memory that is not host visible");
+                    continue
+                }
             };
         }
     }
@@ -1617,17 +1986,20 @@ impl hal::Device<Backend> for Device {
 
             for item in iter {
                 let (memory, ref generic_range) = *item.borrow();
-                let range = memory.resolve(generic_range);
+                let range = memory.resolve_aligned(generic_range);
                 debug!("\trange {:?}", range);
 
                 match memory.heap {
-                    n::MemoryHeap::Native(_) => unimplemented!(),
                     n::MemoryHeap::Public(mt, ref cpu_buffer) if 1<<mt.0 != MemoryTypes::SHARED.bits() as usize => {
                         num_syncs += 1;
                         encoder.synchronize_resource(cpu_buffer);
                     }
                     n::MemoryHeap::Public(..) => continue,
-                    n::MemoryHeap::Private => panic!("Can't map private memory!"),
+                    n::MemoryHeap::Native(_) |
+                    n::MemoryHeap::Private => {
+                        error!("Unable to invalidate memory that is not host visible");
+                        continue
+                    }
                 };
             }
             encoder.end_encoding();
@@ -1645,11 +2017,16 @@ impl hal::Device<Backend> for Device {
         n::Semaphore {
             // Semaphore synchronization between command buffers of the same queue
             // is useless, don't bother even creating one.
-            system: if self.private_caps.exposed_queues > 1 {
+            system: if self.private_caps.exposed_queues > 1 && !self.private_caps.events {
                 Some(n::SystemSemaphore::new())
             } else {
                 None
             },
+            event: if self.private_caps.exposed_queues > 1 && self.private_caps.events {
+                Some(n::Event::new(&*self.shared.device.lock()))
+            } else {
+                None
+            },
             image_ready: Arc::new(Mutex::new(None)),
         }
     }
@@ -1663,18 +2040,22 @@ impl hal::Device<Backend> for Device {
 
         if self.private_caps.argument_buffers {
             let mut arguments = Vec::new();
+            let mut index = 0;
+            let mut has_dynamic_buffers = false;
             for desc_range in descriptor_ranges {
                 let desc = desc_range.borrow();
-                let offset_ref = match desc.ty {
-                    pso::DescriptorType::Sampler => &mut counters.samplers,
-                    pso::DescriptorType::SampledImage => &mut counters.textures,
-                    pso::DescriptorType::UniformBuffer | pso::DescriptorType::StorageBuffer => &mut counters.buffers,
-                    _ => unimplemented!()
-                };
-                let index = *offset_ref;
-                *offset_ref += desc.count as n::PoolResourceIndex;
-                let arg_desc = Self::describe_argument(desc.ty, index as _, desc.count);
-                arguments.push(arg_desc);
+                // sets with dynamic buffers are emulated, and we don't know which of
+                // the other descriptors they have, so all of them are counted
+                let content = n::DescriptorContent::from(desc.ty);
+                counters.add_many(content, desc.count as pso::DescriptorBinding);
+                if content.contains(n::DescriptorContent::DYNAMIC_BUFFER) {
+                    has_dynamic_buffers = true;
+                } else {
+                    index += Self::describe_arguments(desc.ty, index, desc.count, &mut arguments);
+                }
+            }
+            if arguments.is_empty() {
+                return n::DescriptorPool::new_emulated(counters)
             }
 
             let device = self.shared.device.lock();
@@ -1687,6 +2068,11 @@ impl hal::Device<Backend> for Device {
             n::DescriptorPool::ArgumentBuffer {
                 raw,
                 range_allocator: RangeAllocator::new(0..total_size),
+                emulated: if has_dynamic_buffers {
+                    Some(Box::new(n::DescriptorPool::new_emulated(counters)))
+                } else {
+                    None
+                },
             }
         } else {
             for desc_range in descriptor_ranges {
@@ -1709,22 +2095,79 @@ impl hal::Device<Backend> for Device {
         J: IntoIterator,
         J::Item: Borrow<n::Sampler>,
     {
-        if self.private_caps.argument_buffers {
-            let mut stage_flags = pso::ShaderStageFlags::empty();
-            let arguments = binding_iter
+        let set_layout_bindings = binding_iter
+            .into_iter()
+            .map(|slb| slb.borrow().clone())
+            .collect::<Vec<_>>();
+        // dynamic offsets can't be applied to the buffer addresses baked into
+        // an argument buffer, so these sets fall back to the emulated layout
+        let has_dynamic_buffers = set_layout_bindings
+            .iter()
+            .any(|slb| n::DescriptorContent::from(slb.ty).contains(n::DescriptorContent::DYNAMIC_BUFFER));
+
+        if self.private_caps.argument_buffers && !has_dynamic_buffers {
+            // immutable samplers come in the order of the bindings as given,
+            // so they need to be assigned before the bindings are sorted
+            let mut immutable_sampler_iter = immutable_samplers.into_iter();
+            let mut slbs = set_layout_bindings
                 .into_iter()
-                .map(|desc| {
-                    let desc = desc.borrow();
-                    stage_flags |= desc.stage_flags;
-                    Self::describe_argument(desc.ty, desc.binding, desc.count)
+                .map(|slb| {
+                    let slb_samplers = if slb.immutable_samplers {
+                        immutable_sampler_iter
+                            .by_ref()
+                            .take(slb.count)
+                            .map(|sm| sm.borrow().0.clone())
+                            .collect()
+                    } else {
+                        Vec::new()
+                    };
+                    (slb, slb_samplers)
                 })
                 .collect::<Vec<_>>();
+            slbs.sort_by_key(|&(ref slb, _)| slb.binding);
+
+            let mut stage_flags = pso::ShaderStageFlags::empty();
+            let mut arguments = Vec::new();
+            let mut bindings = Vec::with_capacity(slbs.len());
+            let mut samplers = Vec::new();
+            let mut index = 0;
+            for &(ref slb, ref slb_samplers) in &slbs {
+                stage_flags |= slb.stage_flags;
+                let mut content = native::DescriptorContent::from(slb.ty);
+                if slb.immutable_samplers {
+                    content |= native::DescriptorContent::IMMUTABLE_SAMPLER;
+                    // samplers follow the textures for combined image samplers
+                    let sampler_index = if content.contains(native::DescriptorContent::TEXTURE) {
+                        index + slb.count as NSUInteger
+                    } else {
+                        index
+                    };
+                    samplers.extend(slb_samplers
+                        .iter()
+                        .enumerate()
+                        .map(|(i, sm)| (sampler_index + i as NSUInteger, sm.clone()))
+                    );
+                }
+                bindings.push(n::ArgumentLayout {
+                    binding: slb.binding,
+                    res_index: index,
+                    count: slb.count,
+                    content,
+                });
+                index += Self::describe_arguments(slb.ty, index, slb.count, &mut arguments);
+            }
+
             let arg_array = metal::Array::from_owned_slice(&arguments);
             let encoder = self.shared.device
                 .lock()
                 .new_argument_encoder(&arg_array);
 
-            n::DescriptorSetLayout::ArgumentBuffer(encoder, stage_flags)
+            n::DescriptorSetLayout::ArgumentBuffer {
+                encoder,
+                stage_flags,
+                bindings: Arc::new(bindings),
+                immutable_samplers: samplers,
+            }
         } else {
             struct TempSampler {
                 sampler: metal::SamplerState,
@@ -1735,8 +2178,7 @@ impl hal::Device<Backend> for Device {
             let mut tmp_samplers = Vec::new();
             let mut desc_layouts = Vec::new();
 
-            for set_layout_binding in binding_iter {
-                let slb = set_layout_binding.borrow();
+            for slb in &set_layout_bindings {
                 let mut content = native::DescriptorContent::from(slb.ty);
                 if slb.immutable_samplers {
                     content |= native::DescriptorContent::IMMUTABLE_SAMPLER;
@@ -1825,38 +2267,58 @@ impl hal::Device<Backend> for Device {
                                 data.textures[counters.textures as usize] = Some((AsNative::from(view.raw.as_ref()), image::Layout::General));
                             }
                             pso::Descriptor::Buffer(buf, ref range) => {
-                                let start = buf.range.start + range.start.unwrap_or(0);
-                                if let Some(end) = range.end {
-                                    debug_assert!(buf.range.start + end <= buf.range.end);
-                                };
-                                data.buffers[counters.buffers as usize] = Some((AsNative::from(buf.raw.as_ref()), start));
+                                if let Some(sub) = Self::resolve_descriptor_range(buf, range) {
+                                    data.buffers[counters.buffers as usize] = Some((AsNative::from(buf.raw.as_ref()), sub));
+                                }
                             }
                         }
                         counters.add(layout.content);
                     }
                 }
-                n::DescriptorSet::ArgumentBuffer { ref raw, offset, ref encoder, .. } => {
+                n::DescriptorSet::ArgumentBuffer { ref raw, offset, ref encoder, ref bindings, .. } => {
                     debug_assert!(self.private_caps.argument_buffers);
 
                     encoder.set_argument_buffer(raw, offset);
-                    //TODO: range checks, need to keep some layout metadata around
-                    assert_eq!(write.array_offset, 0); //TODO
+                    let mut binding_index = bindings
+                        .iter()
+                        .position(|bl| bl.binding == write.binding)
+                        .expect("Unknown descriptor binding");
+                    let mut array_index = write.array_offset;
 
                     for descriptor in write.descriptors {
+                        // writes overflowing the array continue into the next binding
+                        while array_index >= bindings[binding_index].count {
+                            array_index -= bindings[binding_index].count;
+                            binding_index += 1;
+                        }
+                        let bl = &bindings[binding_index];
+                        let index = bl.res_index + array_index as NSUInteger;
+
                         match *descriptor.borrow() {
                             pso::Descriptor::Sampler(sampler) => {
-                                encoder.set_sampler_states(&[&sampler.0], write.binding as _);
+                                debug_assert!(!bl.content.contains(n::DescriptorContent::IMMUTABLE_SAMPLER));
+                                encoder.set_sampler_states(&[&sampler.0], index);
                             }
                             pso::Descriptor::Image(image, _layout) => {
-                                encoder.set_textures(&[&image.raw], write.binding as _);
+                                encoder.set_textures(&[&image.raw], index);
+                            }
+                            pso::Descriptor::CombinedImageSampler(image, _layout, sampler) => {
+                                encoder.set_textures(&[&image.raw], index);
+                                if !bl.content.contains(n::DescriptorContent::IMMUTABLE_SAMPLER) {
+                                    encoder.set_sampler_states(&[&sampler.0], index + bl.count as NSUInteger);
+                                }
+                            }
+                            pso::Descriptor::UniformTexelBuffer(view) |
+                            pso::Descriptor::StorageTexelBuffer(view) => {
+                                encoder.set_textures(&[&view.raw], index);
                             }
                             pso::Descriptor::Buffer(buffer, ref range) => {
-                                encoder.set_buffer(&buffer.raw, buffer.range.start + range.start.unwrap_or(0), write.binding as _);
+                                if let Some(sub) = Self::resolve_descriptor_range(buffer, range) {
+                                    encoder.set_buffer(&buffer.raw, sub.start, index);
+                                }
                             }
-                            pso::Descriptor::CombinedImageSampler(..) |
-                            pso::Descriptor::UniformTexelBuffer(..) |
-                            pso::Descriptor::StorageTexelBuffer(..) => unimplemented!(),
                         }
+                        array_index += 1;
                     }
                 }
             }
@@ -1868,8 +2330,76 @@ impl hal::Device<Backend> for Device {
         I: IntoIterator,
         I::Item: Borrow<pso::DescriptorSetCopy<'a, Backend>>,
     {
-        for _copy in copies {
-            unimplemented!()
+        fn locate(
+            layouts: &[n::DescriptorLayout],
+            resources: &n::ResourceData<Range<n::PoolResourceIndex>>,
+            binding: pso::DescriptorBinding,
+            array_index: pso::DescriptorArrayIndex,
+        ) -> (usize, n::ResourceData<n::PoolResourceIndex>) {
+            let mut counters = resources.map(|r| r.start);
+            for (i, layout) in layouts.iter().enumerate() {
+                if layout.binding == binding && layout.array_index == array_index {
+                    return (i, counters)
+                }
+                counters.add(layout.content);
+            }
+            panic!("Unknown descriptor binding {} at array index {}", binding, array_index)
+        }
+
+        debug!("copy_descriptor_sets");
+        for copy in copies {
+            let copy = copy.borrow();
+            match (copy.src_set, copy.dst_set) {
+                (
+                    &n::DescriptorSet::Emulated { pool: ref src_pool, layouts: ref src_layouts, resources: ref src_resources },
+                    &n::DescriptorSet::Emulated { pool: ref dst_pool, layouts: ref dst_layouts, resources: ref dst_resources },
+                ) => {
+                    let (src_start, mut src_counters) = locate(src_layouts, src_resources, copy.src_binding, copy.src_array_offset);
+                    let (dst_start, mut dst_counters) = locate(dst_layouts, dst_resources, copy.dst_binding, copy.dst_array_offset);
+
+                    // gather the descriptors first, since both sets may live in the same pool
+                    let descriptors = {
+                        let data = src_pool.read();
+                        src_layouts[src_start ..]
+                            .iter()
+                            .take(copy.count)
+                            .map(|layout| {
+                                let sampler = if layout.content.contains(n::DescriptorContent::SAMPLER) {
+                                    data.samplers[src_counters.samplers as usize]
+                                } else { None };
+                                let texture = if layout.content.contains(n::DescriptorContent::TEXTURE) {
+                                    data.textures[src_counters.textures as usize]
+                                } else { None };
+                                let buffer = if layout.content.contains(n::DescriptorContent::BUFFER) {
+                                    data.buffers[src_counters.buffers as usize].clone()
+                                } else { None };
+                                src_counters.add(layout.content);
+                                (sampler, texture, buffer)
+                            })
+                            .collect::<Vec<_>>()
+                    };
+
+                    let mut data = dst_pool.write();
+                    for (layout, (sampler, texture, buffer)) in dst_layouts[dst_start ..].iter().zip(descriptors) {
+                        trace!("\t{:?}", layout);
+                        if layout.content.contains(n::DescriptorContent::SAMPLER) &&
+                            !layout.content.contains(n::DescriptorContent::IMMUTABLE_SAMPLER)
+                        {
+                            data.samplers[dst_counters.samplers as usize] = sampler;
+                        }
+                        if layout.content.contains(n::DescriptorContent::TEXTURE) {
+                            data.textures[dst_counters.textures as usize] = texture;
+                        }
+                        if layout.content.contains(n::DescriptorContent::BUFFER) {
+                            data.buffers[dst_counters.buffers as usize] = buffer;
+                        }
+                        dst_counters.add(layout.content);
+                    }
+                }
+                _ => {
+                    error!("Copying argument buffer descriptor sets is not supported yet");
+                }
+            }
         }
     }
 
@@ -1906,12 +2436,15 @@ impl hal::Device<Backend> for Device {
         debug!("allocate_memory type {:?} of size {}", memory_type, size);
 
         // Heaps cannot be used for CPU coherent resources
-        //TEMP: MacOS supports Private only, iOS and tvOS can do private/shared
-        let heap = if self.private_caps.resource_heaps && storage != MTLStorageMode::Shared && false {
+        //Note: MacOS supports Private only, iOS and tvOS can do private/shared
+        let heap = if self.private_caps.resource_heaps && storage == MTLStorageMode::Private {
             let descriptor = metal::HeapDescriptor::new();
             descriptor.set_storage_mode(storage);
             descriptor.set_cpu_cache_mode(cache);
             descriptor.set_size(size);
+            unsafe {
+                msg_send![descriptor, setHazardTrackingMode: MTL_HAZARD_TRACKING_MODE_TRACKED];
+            }
             let heap_raw = device.new_heap(&descriptor);
             n::MemoryHeap::Native(heap_raw)
         } else if storage == MTLStorageMode::Private {
@@ -1940,22 +2473,26 @@ impl hal::Device<Backend> for Device {
         Ok(n::UnboundBuffer {
             size,
             usage,
+            name: String::new(),
         })
     }
 
+    fn set_buffer_name(&self, buffer: &mut n::UnboundBuffer, name: &str) {
+        // the label is applied once memory is bound, unless the buffer
+        // is sub-allocated from a CPU-visible memory
+        buffer.name = name.to_string();
+    }
+
     fn get_buffer_requirements(&self, buffer: &n::UnboundBuffer) -> memory::Requirements {
         let mut max_size = buffer.size;
         let mut max_alignment = self.private_caps.buffer_alignment;
 
-        if self.private_caps.resource_heaps {
+        if !self.heap_resource_options.is_empty() {
             // We don't know what memory type the user will try to allocate the buffer with, so we test them
             // all get the most stringent ones.
-            for (i, _mt) in self.memory_types.iter().enumerate() {
-                let (storage, cache) = MemoryTypes::describe(i);
-                let options = conv::resource_options_from_storage_and_cache(storage, cache);
-                let requirements = self.shared.device
-                    .lock()
-                    .heap_buffer_size_and_align(buffer.size, options);
+            let device = self.shared.device.lock();
+            for &options in &self.heap_resource_options {
+                let requirements = device.heap_buffer_size_and_align(buffer.size, options);
                 max_size = cmp::max(max_size, requirements.size);
                 max_alignment = cmp::max(max_alignment, requirements.align);
             }
@@ -1973,9 +2510,9 @@ impl hal::Device<Backend> for Device {
             size: (max_size + SIZE_MASK) & !SIZE_MASK,
             alignment: max_alignment,
             type_mask: if !supports_texel_view || self.private_caps.shared_textures {
-                MemoryTypes::all().bits()
+                self.exposed_memory_types().bits()
             } else {
-                (MemoryTypes::all() ^ MemoryTypes::SHARED).bits()
+                (self.exposed_memory_types() - MemoryTypes::SHARED).bits()
             },
         }
     }
@@ -1990,6 +2527,8 @@ impl hal::Device<Backend> for Device {
                     heap.storage_mode(),
                     heap.cpu_cache_mode(),
                 );
+                // Note: the heap picks the placement on its own, ignoring `offset`,
+                // see `MemoryHeap::Native` for the aliasing limitations.
                 let raw = heap.new_buffer(buffer.size, resource_options)
                     .unwrap_or_else(|| {
                         // TODO: disable hazard tracking?
@@ -1997,6 +2536,9 @@ impl hal::Device<Backend> for Device {
                             .lock()
                             .new_buffer(buffer.size, resource_options)
                     });
+                if !buffer.name.is_empty() {
+                    raw.set_label(&buffer.name);
+                }
                 (raw, resource_options, 0 .. buffer.size) //TODO?
             }
             n::MemoryHeap::Public(mt, ref cpu_buffer) => {
@@ -2012,6 +2554,9 @@ impl hal::Device<Backend> for Device {
                 let raw = self.shared.device
                     .lock()
                     .new_buffer(buffer.size, options);
+                if !buffer.name.is_empty() {
+                    raw.set_label(&buffer.name);
+                }
                 (raw, options, 0 .. buffer.size)
             }
         };
@@ -2025,6 +2570,7 @@ impl hal::Device<Backend> for Device {
 
     fn destroy_buffer(&self, buffer: n::Buffer) {
         debug!("destroy_buffer {:?} occupying memory {:?}", buffer.raw.as_ptr(), buffer.range);
+        self.release_heap_resource(&buffer.raw);
     }
 
     fn create_buffer_view<R: RangeArg<u64>>(
@@ -2040,8 +2586,11 @@ impl hal::Device<Backend> for Device {
             None => return Err(buffer::ViewCreationError::UnsupportedFormat { format: format_maybe }),
         };
         let format_desc = format.surface_desc();
-        if format_desc.aspects != format::Aspects::COLOR || format_desc.is_compressed() {
-            // Vadlidator says "Linear texture: cannot create compressed, depth, or stencil textures"
+        // Vadlidator says "Linear texture: cannot create compressed, depth, or stencil textures"
+        if !format_desc.aspects.contains(format::Aspects::COLOR) {
+            return Err(buffer::ViewCreationError::NonColorFormat { format })
+        }
+        if format_desc.is_compressed() {
             return Err(buffer::ViewCreationError::UnsupportedFormat { format: format_maybe })
         }
 
@@ -2063,7 +2612,13 @@ impl hal::Device<Backend> for Device {
         descriptor.set_storage_mode(buffer.raw.storage_mode());
         descriptor.set_usage(metal::MTLTextureUsage::ShaderRead);
 
-        let align_mask = self.private_caps.buffer_alignment - 1;
+        let alignment = self.private_caps
+            .linear_texture_alignment(&self.shared.device.lock(), mtl_format);
+        if start & (alignment - 1) != 0 {
+            error!("Buffer view offset {} is not aligned to {} for {:?}", start, alignment, format);
+            return Err(buffer::ViewCreationError::Unsupported)
+        }
+        let align_mask = alignment - 1;
         let stride = (col_count * (format_desc.bits as u64 / 8) + align_mask) & !align_mask;
 
         Ok(n::BufferView {
@@ -2092,6 +2647,12 @@ impl hal::Device<Backend> for Device {
             .map_format(format)
             .ok_or(image::CreationError::Format(format))?;
 
+        if mip_levels > kind.num_levels() {
+            error!("Image {:?} can't have {} mip levels, maximum is {}",
+                kind, mip_levels, kind.num_levels());
+            return Err(image::CreationError::Kind)
+        }
+
         let descriptor = metal::TextureDescriptor::new();
 
         let (mtl_type, num_layers) = match kind {
@@ -2152,7 +2713,7 @@ impl hal::Device<Backend> for Device {
 
         let host_usage = image::Usage::TRANSFER_SRC | image::Usage::TRANSFER_DST;
         let host_visible = mtl_type == MTLTextureType::D2 &&
-            mip_levels == 1 && num_layers.is_none() &&
+            num_layers.is_none() &&
             format_desc.aspects.contains(format::Aspects::COLOR) &&
             tiling == image::Tiling::Linear &&
             host_usage.contains(usage);
@@ -2163,9 +2724,15 @@ impl hal::Device<Backend> for Device {
             kind,
             mip_sizes,
             host_visible,
+            name: String::new(),
         })
     }
 
+    fn set_image_name(&self, image: &mut n::UnboundImage, name: &str) {
+        // the label is applied to the texture once memory is bound
+        image.name = name.to_string();
+    }
+
     fn get_image_requirements(&self, image: &n::UnboundImage) -> memory::Requirements {
         if self.private_caps.resource_heaps {
             // We don't know what memory type the user will try to allocate the image with, so we test them
@@ -2173,10 +2740,14 @@ impl hal::Device<Backend> for Device {
             let mut max_size = 0;
             let mut max_alignment = 0;
             let types = if image.host_visible {
-                MemoryTypes::all()
+                self.exposed_memory_types()
             } else {
                 MemoryTypes::PRIVATE
             };
+            // The device is locked once for all the queries. The descriptor only belongs
+            // to this image, so changing its storage mode between them doesn't affect
+            // anything else, and `bind_image_memory` sets the final mode from the heap.
+            let device = self.shared.device.lock();
             for (i, _) in self.memory_types.iter().enumerate() {
                 if !types.contains(MemoryTypes::from_bits(1 << i).unwrap()) {
                     continue
@@ -2185,9 +2756,7 @@ impl hal::Device<Backend> for Device {
                 image.texture_desc.set_storage_mode(storage);
                 image.texture_desc.set_cpu_cache_mode(cache_mode);
 
-                let requirements = self.shared.device
-                    .lock()
-                    .heap_texture_size_and_align(&image.texture_desc);
+                let requirements = device.heap_texture_size_and_align(&image.texture_desc);
                 max_size = cmp::max(max_size, requirements.size);
                 max_alignment = cmp::max(max_alignment, requirements.align);
             }
@@ -2197,12 +2766,13 @@ impl hal::Device<Backend> for Device {
                 type_mask: types.bits(),
             }
         } else if image.host_visible {
-            assert_eq!(image.mip_sizes.len(), 1);
+            // all the mip levels are packed together, see `Image::level_offset`
             let mask = self.private_caps.buffer_alignment - 1;
+            let size: u64 = image.mip_sizes.iter().sum();
             memory::Requirements {
-                size: (image.mip_sizes[0] + mask) & !mask,
+                size: (size + mask) & !mask,
                 alignment: self.private_caps.buffer_alignment,
-                type_mask: MemoryTypes::all().bits(),
+                type_mask: self.exposed_memory_types().bits(),
             }
         } else {
             memory::Requirements {
@@ -2216,13 +2786,8 @@ impl hal::Device<Backend> for Device {
     fn get_image_subresource_footprint(
         &self, image: &n::Image, sub: image::Subresource
     ) -> image::SubresourceFootprint {
-        let num_layers = image.kind.num_layers() as buffer::Offset;
-        let level_offset = (0 .. sub.level).fold(0, |offset, level| {
-            let pitches = image.pitches(level);
-            offset + num_layers * pitches[3]
-        });
         let pitches = image.pitches(sub.level);
-        let layer_offset = level_offset + sub.layer as buffer::Offset * pitches[3];
+        let layer_offset = image.level_offset(sub.level) + sub.layer as buffer::Offset * pitches[3];
         image::SubresourceFootprint {
             slice: layer_offset .. layer_offset + pitches[3],
             row_pitch: pitches[1] as _,
@@ -2243,31 +2808,36 @@ impl hal::Device<Backend> for Device {
                     heap.storage_mode(),
                     heap.cpu_cache_mode());
                 image.texture_desc.set_resource_options(resource_options);
-                n::ImageLike::Texture(
-                    heap.new_texture(&image.texture_desc)
-                        .unwrap_or_else(|| {
-                            // TODO: disable hazard tracking?
-                            self.shared.device
-                                .lock()
-                                .new_texture(&image.texture_desc)
-                        })
-                )
+                // Note: the heap picks the placement on its own, see `MemoryHeap::Native`
+                let raw = heap.new_texture(&image.texture_desc)
+                    .unwrap_or_else(|| {
+                        // TODO: disable hazard tracking?
+                        self.shared.device
+                            .lock()
+                            .new_texture(&image.texture_desc)
+                    });
+                if !image.name.is_empty() {
+                    raw.set_label(&image.name);
+                }
+                n::ImageLike::Texture(raw)
             },
             n::MemoryHeap::Public(_memory_type, ref cpu_buffer) => {
-                assert_eq!(image.mip_sizes.len(), 1);
+                let size: u64 = image.mip_sizes.iter().sum();
                 n::ImageLike::Buffer(n::Buffer {
                     raw: cpu_buffer.clone(),
-                    range: offset .. offset + image.mip_sizes[0] as u64,
+                    range: offset .. offset + size,
                     options: MTLResourceOptions::StorageModeShared,
                 })
             }
             n::MemoryHeap::Private => {
                 image.texture_desc.set_storage_mode(MTLStorageMode::Private);
-                n::ImageLike::Texture(
-                    self.shared.device
-                        .lock()
-                        .new_texture(&image.texture_desc)
-                )
+                let raw = self.shared.device
+                    .lock()
+                    .new_texture(&image.texture_desc);
+                if !image.name.is_empty() {
+                    raw.set_label(&image.name);
+                }
+                n::ImageLike::Texture(raw)
             }
         };
 
@@ -2287,7 +2857,10 @@ impl hal::Device<Backend> for Device {
         })
     }
 
-    fn destroy_image(&self, _image: n::Image) {
+    fn destroy_image(&self, image: n::Image) {
+        if let n::ImageLike::Texture(ref raw) = image.like {
+            self.release_heap_resource(raw);
+        }
     }
 
     fn create_image_view(
@@ -2298,8 +2871,8 @@ impl hal::Device<Backend> for Device {
         swizzle: format::Swizzle,
         range: image::SubresourceRange,
     ) -> Result<n::ImageView, image::ViewError> {
-        let mtl_format = match self.private_caps.map_format_with_swizzle(format, swizzle) {
-            Some(f) => f,
+        let (mtl_format, mtl_swizzle) = match self.private_caps.map_format_with_swizzle(format, swizzle) {
+            Some(pair) => pair,
             None => {
                 error!("failed to swizzle format {:?} with {:?}", format, swizzle);
                 return Err(image::ViewError::BadFormat);
@@ -2323,18 +2896,29 @@ impl hal::Device<Backend> for Device {
             // Also helps working around Metal bugs with aliased array textures.
             raw.to_owned()
         } else {
-            raw.new_texture_view_from_slice(
-                mtl_format,
-                mtl_type,
-                NSRange {
-                    location: range.levels.start as _,
-                    length: (range.levels.end - range.levels.start) as _,
-                },
-                NSRange {
-                    location: range.layers.start as _,
-                    length: (range.layers.end - range.layers.start) as _,
-                },
-            )
+            let levels = NSRange {
+                location: range.levels.start as _,
+                length: (range.levels.end - range.levels.start) as _,
+            };
+            let slices = NSRange {
+                location: range.layers.start as _,
+                length: (range.layers.end - range.layers.start) as _,
+            };
+            if mtl_swizzle != format::Swizzle::NO {
+                let channels = conv::map_swizzle(mtl_swizzle);
+                unsafe {
+                    let view: *mut metal::MTLTexture = msg_send![raw,
+                        newTextureViewWithPixelFormat: mtl_format
+                        textureType: mtl_type
+                        levels: levels
+                        slices: slices
+                        swizzle: channels
+                    ];
+                    metal::Texture::from_ptr(view)
+                }
+            } else {
+                raw.new_texture_view_from_slice(mtl_format, mtl_type, levels, slices)
+            }
         };
 
         Ok(n::ImageView { raw: view, mtl_format })
@@ -2344,7 +2928,12 @@ impl hal::Device<Backend> for Device {
     }
 
     fn create_fence(&self, signaled: bool) -> n::Fence {
-        n::Fence(RefCell::new(n::FenceInner::Idle { signaled }))
+        let event = if cfg!(feature = "native-fence") && self.private_caps.events {
+            Some(n::Event::new_shared(&*self.shared.device.lock()))
+        } else {
+            None
+        };
+        n::Fence(RefCell::new(n::FenceInner::Idle { signaled }), event)
     }
     fn reset_fence(&self, fence: &n::Fence) {
         *fence.0.borrow_mut() = n::FenceInner::Idle { signaled: false };
@@ -2360,6 +2949,9 @@ impl hal::Device<Backend> for Device {
             native::FenceInner::Idle { signaled } => return signaled,
             native::FenceInner::Pending(ref cmd_buf) => cmd_buf,
         };
+        if let Some(ref event) = fence.1 {
+            return event.wait_signaled(timeout_ns);
+        }
         if timeout_ns == !0 {
             cmd_buf.wait_until_completed();
             return true
@@ -2377,12 +2969,17 @@ impl hal::Device<Backend> for Device {
         }
     }
     fn get_fence_status(&self, fence: &n::Fence) -> bool {
-        match *fence.0.borrow() {
-            native::FenceInner::Idle { signaled } => signaled,
-            native::FenceInner::Pending(ref cmd_buf) => match cmd_buf.status() {
-                metal::MTLCommandBufferStatus::Completed => true,
-                _ => false,
-            },
+        let inner = fence.0.borrow();
+        let cmd_buf = match *inner {
+            native::FenceInner::Idle { signaled } => return signaled,
+            native::FenceInner::Pending(ref cmd_buf) => cmd_buf,
+        };
+        if let Some(ref event) = fence.1 {
+            return event.is_signaled();
+        }
+        match cmd_buf.status() {
+            metal::MTLCommandBufferStatus::Completed => true,
+            _ => false,
         }
     }
     fn destroy_fence(&self, _fence: n::Fence) {
@@ -2438,7 +3035,8 @@ impl hal::Device<Backend> for Device {
 
                 let size_data = mem::size_of::<u64>() as buffer::Offset;
                 if stride == size_data && flags.contains(query::ResultFlags::BITS_64) &&
-                    !flags.contains(query::ResultFlags::WITH_AVAILABILITY)
+                    !flags.contains(query::ResultFlags::WITH_AVAILABILITY) &&
+                    (is_ready || flags.contains(query::ResultFlags::PARTIAL))
                 {
                     // if stride is matching, copy everything in one go
                     unsafe {
@@ -2461,15 +3059,21 @@ impl hal::Device<Backend> for Device {
                                 .offset(visibility.availability_offset as isize);
                             *(base as *const u32).offset(absolute_index)
                         };
+                        // unavailable results are only written out if partial results are requested
+                        let write_value = availability != 0 || flags.contains(query::ResultFlags::PARTIAL);
                         let data_ptr = data[i as usize * stride as usize ..].as_mut_ptr();
                         unsafe {
                             if flags.contains(query::ResultFlags::BITS_64) {
-                                *(data_ptr as *mut u64) = value;
+                                if write_value {
+                                    *(data_ptr as *mut u64) = value;
+                                }
                                 if flags.contains(query::ResultFlags::WITH_AVAILABILITY) {
                                     *(data_ptr as *mut u64).offset(1) = availability as u64;
                                 }
                             } else {
-                                *(data_ptr as *mut u32) = value as u32;
+                                if write_value {
+                                    *(data_ptr as *mut u32) = value as u32;
+                                }
                                 if flags.contains(query::ResultFlags::WITH_AVAILABILITY) {
                                     *(data_ptr as *mut u32).offset(1) = availability;
                                 }
@@ -2508,3 +3112,87 @@ fn test_send_sync() {
     fn foo<T: Send+Sync>() {}
     foo::<Device>()
 }
+
+#[test]
+fn test_parse_malformed_shader() {
+    // not even the SPIR-V magic number is right
+    let data = [0xDEu8, 0xAD, 0xBE, 0xEF, 0, 0, 0, 0];
+    assert!(Device::parse_shader(&data).is_err());
+}
+
+#[test]
+fn test_load_malformed_shader() {
+    // well-formed words, but the entry point refers to a function that doesn't exist
+    let words: [u32; 15] = [
+        0x0723_0203, 0x0001_0000, 0, 5, 0,
+        (2 << 16) | 17, 1, // OpCapability Shader
+        (3 << 16) | 14, 0, 1, // OpMemoryModel Logical GLSL450
+        (5 << 16) | 15, 0, 4, 0x6E69_616D, 0, // OpEntryPoint Vertex %4 "main"
+    ];
+    let data = unsafe {
+        slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * mem::size_of::<u32>())
+    };
+
+    let shared = Arc::new(Shared::new(metal::Device::system_default()));
+    let physical_device = PhysicalDevice::new(shared);
+    let gpu = hal::PhysicalDevice::open(&physical_device, &[(&QueueFamily {}, &[1.0])])
+        .unwrap();
+    let device = gpu.device;
+    let module = hal::Device::create_shader_module(&device, data).unwrap();
+    let layout = hal::Device::create_pipeline_layout(
+        &device,
+        iter::empty::<n::DescriptorSetLayout>(),
+        iter::empty::<(pso::ShaderStageFlags, Range<u32>)>(),
+    );
+    let entry_point = pso::EntryPoint {
+        entry: "main",
+        module: &module,
+        specialization: pso::Specialization::default(),
+    };
+
+    match device.load_shader(&entry_point, &layout, MTLPrimitiveTopologyClass::Triangle, None) {
+        Err(pso::CreationError::Shader(_)) => (),
+        Err(other) => panic!("Unexpected error {:?}", other),
+        Ok(_) => panic!("Malformed shader was loaded"),
+    }
+}
+
+#[test]
+fn test_specialization_constant_bool() {
+    let as_bytes = |data: &[u8]| specialization_constant_value(MTLDataType::Bool, data).map(|v| v.into_owned());
+    // 4-byte `VkBool32`
+    assert_eq!(as_bytes(&[0, 0, 0, 0]), Some(vec![0]));
+    assert_eq!(as_bytes(&[0, 1, 0, 0]), Some(vec![1]));
+    // 1-byte booleans are still accepted
+    assert_eq!(as_bytes(&[1]), Some(vec![1]));
+    assert_eq!(as_bytes(&[0, 0]), None);
+}
+
+#[cfg(test)]
+fn test_render_pass(num_attachments: usize) -> n::RenderPass {
+    let attachment = pass::Attachment {
+        format: Some(format::Format::Rgba8Unorm),
+        samples: 1,
+        ops: pass::AttachmentOps::DONT_CARE,
+        stencil_ops: pass::AttachmentOps::DONT_CARE,
+        layouts: image::Layout::Undefined .. image::Layout::Present,
+    };
+    n::RenderPass {
+        attachments: vec![attachment; num_attachments],
+        subpasses: Vec::new(),
+    }
+}
+
+#[test]
+fn test_framebuffer_too_many_attachments() {
+    let render_pass = test_render_pass(2);
+    assert!(check_framebuffer_attachments(&render_pass, 2).is_ok());
+    assert_eq!(check_framebuffer_attachments(&render_pass, 3), Err(FramebufferError));
+}
+
+#[test]
+fn test_framebuffer_too_few_attachments() {
+    let render_pass = test_render_pass(2);
+    assert_eq!(check_framebuffer_attachments(&render_pass, 1), Err(FramebufferError));
+    assert_eq!(check_framebuffer_attachments(&render_pass, 0), Err(FramebufferError));
+}
@@ -191,8 +191,15 @@ impl DepthStencilStates {
     }
 
     pub fn prepare(&self, desc: &pso::DepthStencilDesc, device: &metal::DeviceRef) {
-        self.map.prepare_maybe(desc, || {
-            Self::create_desc(desc)
+        // stencil references are set on the encoder, and the states are always
+        // looked up with dynamic references (see `State::build_depth_stencil`)
+        let mut desc = desc.clone();
+        if let pso::StencilTest::On { ref mut front, ref mut back } = desc.stencil {
+            front.reference = pso::State::Dynamic;
+            back.reference = pso::State::Dynamic;
+        }
+        self.map.prepare_maybe(&desc, || {
+            Self::create_desc(&desc)
                 .map(|raw_desc| {
                     device.new_depth_stencil_state(&raw_desc)
                 })
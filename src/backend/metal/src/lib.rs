@@ -74,6 +74,11 @@ impl Default for OnlineRecording {
 
 const MAX_ACTIVE_COMMAND_BUFFERS: usize = 1 << 14;
 const MAX_VISIBILITY_QUERIES: usize = 1 << 14;
+/// Command buffers are created from the shared `MTLCommandQueue` at recording time,
+/// before the submission queue is known, so only a single queue is exposed.
+const MAX_EXPOSED_QUEUES: usize = 1;
+const MAX_VIEWPORTS: usize = 16;
+const MAX_SAMPLER_ANISOTROPY: u8 = 16;
 
 #[derive(Debug, Clone, Copy)]
 pub struct QueueFamily {}
@@ -83,7 +88,7 @@ impl hal::QueueFamily for QueueFamily {
         hal::QueueType::General
     }
     fn max_queues(&self) -> usize {
-        1
+        MAX_EXPOSED_QUEUES
     }
     fn id(&self) -> QueueFamilyId {
         QueueFamilyId(0)
@@ -105,6 +110,7 @@ struct Shared {
     service_pipes: internal::ServicePipes,
     disabilities: PrivateDisabilities,
     visibility: VisibilityShared,
+    max_viewports: usize,
 }
 
 unsafe impl Send for Shared {}
@@ -135,6 +141,17 @@ impl Shared {
             disabilities: PrivateDisabilities {
                 broken_viewport_near_depth: device.name().starts_with("Intel")
                     && !device.supports_feature_set(feature_macos_10_14),
+                no_base_vertex_instance: !device::BASE_INSTANCE_SUPPORT
+                    .iter()
+                    .any(|&fs| device.supports_feature_set(fs)),
+                no_store_and_resolve: !device::STORE_AND_RESOLVE_SUPPORT
+                    .iter()
+                    .any(|&fs| device.supports_feature_set(fs)),
+            },
+            max_viewports: if device.supports_feature_set(metal::MTLFeatureSet::macOS_GPUFamily1_v3) {
+                MAX_VIEWPORTS
+            } else {
+                1
             },
             device: Mutex::new(device),
             visibility,
@@ -347,6 +364,8 @@ struct PrivateCapabilities {
     exposed_queues: usize,
     resource_heaps: bool,
     argument_buffers: bool,
+    events: bool,
+    texture_swizzle: bool,
     shared_textures: bool,
     base_instance: bool,
     dual_source_blending: bool,
@@ -402,8 +421,19 @@ struct PrivateCapabilities {
     max_textures_per_stage: ResourceIndex,
     max_samplers_per_stage: ResourceIndex,
     buffer_alignment: u64,
+    /// Largest linear texture alignment over the formats usable in buffer views.
+    max_texel_buffer_alignment: u64,
+    /// Offset alignment of buffers bound to the `constant` address space.
+    constant_buffer_alignment: u64,
     max_buffer_size: u64,
+    /// Size of the private memory heap, as recommended by the device.
+    max_private_heap_size: u64,
     max_texture_size: u64,
+    max_texture_3d_size: u64,
+    max_texture_layers: u64,
+    sample_count_mask: u8,
+    max_threads_per_group: [u32; 3],
+    max_viewports: usize,
 }
 
 impl PrivateCapabilities {
@@ -415,11 +445,31 @@ impl PrivateCapabilities {
         let (major, minor) = self.os_version;
         Self::version_at_least(major, minor, needed_major, needed_minor)
     }
+
+    /// Offset and row alignment of a linear texture in the given format.
+    fn linear_texture_alignment(&self, device: &metal::DeviceRef, format: metal::MTLPixelFormat) -> u64 {
+        if (self.os_is_mac && self.has_version_at_least(10, 13)) ||
+            (!self.os_is_mac && self.has_version_at_least(11, 0))
+        {
+            let alignment: cocoa::foundation::NSUInteger = unsafe {
+                msg_send![device, minimumLinearTextureAlignmentForPixelFormat: format]
+            };
+            alignment as u64
+        } else {
+            self.buffer_alignment
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
 struct PrivateDisabilities {
     broken_viewport_near_depth: bool,
+    /// No `baseVertex` and `baseInstance` draw variants, emulated by
+    /// offsetting the vertex buffers instead.
+    no_base_vertex_instance: bool,
+    /// No `StoreAndMultisampleResolve` store action, emulated by a separate
+    /// pass resolving the stored color attachments.
+    no_store_and_resolve: bool,
 }
 
 fn validate_line_width(width: f32) {
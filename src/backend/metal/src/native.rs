@@ -4,10 +4,11 @@ use range_alloc::RangeAllocator;
 use window::SwapchainImage;
 
 use std::cell::RefCell;
-use std::fmt;
-use std::ops::Range;
+use std::{fmt, iter};
+use std::ops::{Deref, Range};
 use std::os::raw::{c_void, c_long};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use hal::{buffer, image, pso};
 use hal::{DescriptorPool as HalDescriptorPool, MemoryTypeId};
@@ -16,13 +17,18 @@ use hal::format::{Format, FormatDesc};
 use hal::pass::{Attachment, AttachmentId};
 use hal::range::RangeArg;
 
+use block::ConcreteBlock;
 use cocoa::foundation::{NSRange, NSUInteger};
 use metal;
+use objc::runtime::Object;
 use parking_lot::{Mutex, RwLock};
 use smallvec::SmallVec;
 use spirv_cross::{msl, spirv};
 
 
+/// Granularity of the memory flushes and invalidations.
+pub const NON_COHERENT_ATOM_SIZE: u64 = 4;
+
 pub type EntryPointMap = FastHashMap<String, spirv::EntryPoint>;
 /// An index of a resource within descriptor pool.
 pub type PoolResourceIndex = u32;
@@ -31,7 +37,12 @@ pub type PoolResourceIndex = u32;
 /// depend on pipeline layout, in which case the value would become `Compiled`.
 pub enum ShaderModule {
     Compiled(ModuleInfo),
-    Raw(Vec<u8>),
+    Raw {
+        data: Vec<u8>,
+        /// Parsed once at creation, and compiled for each distinct set of compiler options.
+        ast: Mutex<spirv::Ast<msl::Target>>,
+        compiled: Mutex<FastHashMap<msl::CompilerOptions, ModuleInfo>>,
+    },
 }
 
 impl fmt::Debug for ShaderModule {
@@ -40,8 +51,8 @@ impl fmt::Debug for ShaderModule {
             ShaderModule::Compiled(_) => {
                 write!(formatter, "ShaderModule::Compiled(..)")
             }
-            ShaderModule::Raw(ref vec) => {
-                write!(formatter, "ShaderModule::Raw(length = {})", vec.len())
+            ShaderModule::Raw { ref data, .. } => {
+                write!(formatter, "ShaderModule::Raw(length = {})", data.len())
             }
         }
     }
@@ -76,6 +87,9 @@ impl SubpassFormats {
 #[derive(Debug)]
 pub struct Subpass {
     pub colors: Vec<(AttachmentId, SubpassOps)>,
+    /// Resolve targets, matching the `colors` one to one if not empty.
+    /// Depth resolves are not described by HAL, so they are not supported.
+    pub resolves: Vec<AttachmentId>,
     pub depth_stencil: Option<(AttachmentId, SubpassOps)>,
     pub inputs: Vec<AttachmentId>,
     pub target_formats: SubpassFormats,
@@ -262,6 +276,7 @@ pub struct GraphicsPipeline {
     pub(crate) fs_lib: Option<metal::Library>,
     pub(crate) raw: metal::RenderPipelineState,
     pub(crate) primitive_type: metal::MTLPrimitiveType,
+    pub(crate) primitive_restart: pso::PrimitiveRestart,
     pub(crate) attribute_buffer_index: ResourceIndex,
     pub(crate) vs_pc_info: Option<PushConstantInfo>,
     pub(crate) ps_pc_info: Option<PushConstantInfo>,
@@ -333,8 +348,16 @@ impl Image {
         let extent = self.kind.extent().at_level(level);
         Self::pitches_impl(extent, self.format_desc)
     }
-    pub(crate) fn byte_offset(&self, offset: image::Offset) -> buffer::Offset {
-        let pitches = Self::pitches_impl(self.kind.extent(), self.format_desc);
+    /// Offset of the mip level data, assuming all levels are tightly packed.
+    pub(crate) fn level_offset(&self, level: image::Level) -> buffer::Offset {
+        let num_layers = self.kind.num_layers() as buffer::Offset;
+        (0 .. level).fold(0, |offset, level| {
+            offset + num_layers * self.pitches(level)[3]
+        })
+    }
+    pub(crate) fn byte_offset(&self, level: image::Level, offset: image::Offset) -> buffer::Offset {
+        let pitches = self.pitches(level);
+        self.level_offset(level) +
         pitches[0] * offset.x as buffer::Offset +
         pitches[1] * offset.y as buffer::Offset +
         pitches[2] * offset.z as buffer::Offset
@@ -396,6 +419,7 @@ unsafe impl Sync for Sampler {}
 #[derive(Debug)]
 pub struct Semaphore {
     pub(crate) system: Option<SystemSemaphore>,
+    pub(crate) event: Option<Event>,
     pub(crate) image_ready: Arc<Mutex<Option<SwapchainImage>>>,
 }
 
@@ -406,6 +430,23 @@ pub struct Buffer {
     pub(crate) options: metal::MTLResourceOptions,
 }
 
+impl Buffer {
+    /// Resolves a descriptor range into absolute offsets within the raw buffer.
+    /// Returns `None` if the range is out of the buffer bounds.
+    pub(crate) fn resolve_range(&self, range: &Range<Option<buffer::Offset>>) -> Option<Range<buffer::Offset>> {
+        let start = self.range.start + range.start.unwrap_or(0);
+        let end = match range.end {
+            Some(end) => self.range.start + end,
+            None => self.range.end,
+        };
+        if start <= end && end <= self.range.end {
+            Some(start .. end)
+        } else {
+            None
+        }
+    }
+}
+
 unsafe impl Send for Buffer {}
 unsafe impl Sync for Buffer {}
 
@@ -419,6 +460,8 @@ pub enum DescriptorPool {
     ArgumentBuffer {
         raw: metal::Buffer,
         range_allocator: RangeAllocator<NSUInteger>,
+        /// Pool for the sets with dynamic buffers, which use emulated layouts.
+        emulated: Option<Box<DescriptorPool>>,
     },
 }
 //TODO: re-evaluate Send/Sync here
@@ -429,7 +472,8 @@ unsafe impl Sync for DescriptorPool {}
 pub struct DescriptorPoolInner {
     pub samplers: Vec<Option<SamplerPtr>>,
     pub textures: Vec<Option<(TexturePtr, image::Layout)>>,
-    pub buffers: Vec<Option<(BufferPtr, buffer::Offset)>>,
+    /// Buffers with the resolved absolute range of each descriptor.
+    pub buffers: Vec<Option<(BufferPtr, Range<buffer::Offset>)>>,
 }
 
 impl DescriptorPool {
@@ -564,18 +608,34 @@ impl HalDescriptorPool<Backend> for DescriptorPool {
                     resources,
                 })
             }
-            DescriptorPool::ArgumentBuffer { ref raw, ref mut range_allocator, } => {
-                let (encoder, stage_flags) = match set_layout {
-                    &DescriptorSetLayout::ArgumentBuffer(ref encoder, stages) => (encoder, stages),
+            DescriptorPool::ArgumentBuffer { ref raw, ref mut range_allocator, ref mut emulated } => {
+                if let DescriptorSetLayout::Emulated(..) = *set_layout {
+                    return match *emulated {
+                        Some(ref mut pool) => pool.allocate_set(set_layout),
+                        None => Err(pso::AllocationError::IncompatibleLayout),
+                    }
+                }
+                let (encoder, stage_flags, bindings, immutable_samplers) = match set_layout {
+                    &DescriptorSetLayout::ArgumentBuffer { ref encoder, stage_flags, ref bindings, ref immutable_samplers } =>
+                        (encoder, stage_flags, bindings, immutable_samplers),
                     _ => return Err(pso::AllocationError::IncompatibleLayout),
                 };
                 match range_allocator.allocate_range(encoder.encoded_length()) {
-                    Ok(range) => Ok(DescriptorSet::ArgumentBuffer {
-                        raw: raw.clone(),
-                        offset: range.start,
-                        encoder: encoder.clone(),
-                        stage_flags,
-                    }),
+                    Ok(range) => {
+                        if !immutable_samplers.is_empty() {
+                            encoder.set_argument_buffer(raw, range.start);
+                            for &(index, ref sampler) in immutable_samplers {
+                                encoder.set_sampler_states(&[sampler], index);
+                            }
+                        }
+                        Ok(DescriptorSet::ArgumentBuffer {
+                            raw: raw.clone(),
+                            offset: range.start,
+                            encoder: encoder.clone(),
+                            stage_flags,
+                            bindings: Arc::clone(bindings),
+                        })
+                    }
                     Err(_) => Err(pso::AllocationError::OutOfPoolMemory),
                 }
             }
@@ -619,11 +679,14 @@ impl HalDescriptorPool<Backend> for DescriptorPool {
                     }
                 }
             }
-            DescriptorPool::ArgumentBuffer { ref mut range_allocator, .. } => {
+            DescriptorPool::ArgumentBuffer { ref mut range_allocator, ref mut emulated, .. } => {
                 for descriptor_set in descriptor_sets {
                     match descriptor_set {
                         DescriptorSet::Emulated{..} => {
-                            panic!("Tried to free a DescriptorSet not given out by this DescriptorPool!")
+                            match *emulated {
+                                Some(ref mut pool) => pool.free_sets(iter::once(descriptor_set)),
+                                None => panic!("Tried to free a DescriptorSet not given out by this DescriptorPool!"),
+                            }
                         }
                         DescriptorSet::ArgumentBuffer { offset, encoder, .. } => {
                             let handle_range = offset .. offset + encoder.encoded_length();
@@ -665,8 +728,11 @@ impl HalDescriptorPool<Backend> for DescriptorPool {
                 allocators.textures.reset();
                 allocators.buffers.reset();
             }
-            DescriptorPool::ArgumentBuffer { ref mut range_allocator, .. } => {
+            DescriptorPool::ArgumentBuffer { ref mut range_allocator, ref mut emulated, .. } => {
                 range_allocator.reset();
+                if let Some(ref mut pool) = *emulated {
+                    pool.reset();
+                }
             }
         }
     }
@@ -720,10 +786,27 @@ pub struct DescriptorLayout {
     pub array_index: pso::DescriptorArrayIndex,
 }
 
+/// Location of a descriptor binding within an argument buffer.
+#[derive(Debug)]
+pub struct ArgumentLayout {
+    pub binding: pso::DescriptorBinding,
+    /// Index of the first argument of the binding. Combined image samplers
+    /// have the textures followed by the samplers.
+    pub res_index: NSUInteger,
+    pub count: pso::DescriptorArrayIndex,
+    pub content: DescriptorContent,
+}
+
 #[derive(Debug)]
 pub enum DescriptorSetLayout {
     Emulated(Arc<Vec<DescriptorLayout>>, Vec<metal::SamplerState>),
-    ArgumentBuffer(metal::ArgumentEncoder, pso::ShaderStageFlags),
+    ArgumentBuffer {
+        encoder: metal::ArgumentEncoder,
+        stage_flags: pso::ShaderStageFlags,
+        bindings: Arc<Vec<ArgumentLayout>>,
+        /// Immutable samplers with their argument indices.
+        immutable_samplers: Vec<(NSUInteger, metal::SamplerState)>,
+    },
 }
 unsafe impl Send for DescriptorSetLayout {}
 unsafe impl Sync for DescriptorSetLayout {}
@@ -740,6 +823,7 @@ pub enum DescriptorSet {
         offset: NSUInteger,
         encoder: metal::ArgumentEncoder,
         stage_flags: pso::ShaderStageFlags,
+        bindings: Arc<Vec<ArgumentLayout>>,
     },
 }
 unsafe impl Send for DescriptorSet {}
@@ -763,6 +847,14 @@ impl Memory {
     pub(crate) fn resolve<R: RangeArg<u64>>(&self, range: &R) -> Range<u64> {
         *range.start().unwrap_or(&0) .. *range.end().unwrap_or(&self.size)
     }
+
+    /// Resolve the range and expand it to the `non_coherent_atom_size` boundaries,
+    /// since Metal synchronizes the memory in whole words.
+    pub(crate) fn resolve_aligned<R: RangeArg<u64>>(&self, range: &R) -> Range<u64> {
+        let range = self.resolve(range);
+        let mask = NON_COHERENT_ATOM_SIZE - 1;
+        (range.start & !mask) .. ((range.end + mask) & !mask).min(self.size)
+    }
 }
 
 unsafe impl Send for Memory {}
@@ -772,6 +864,11 @@ unsafe impl Sync for Memory {}
 pub(crate) enum MemoryHeap {
     Private,
     Public(MemoryTypeId, metal::Buffer),
+    /// Metal picks the placement of the resources in an automatic heap, so the
+    /// binding offsets are ignored and resources bound to overlapping ranges
+    /// don't alias. Explicit aliasing would need placement heaps (macOS 10.15,
+    /// iOS 13) and fences between the uses of aliased resources, which we don't
+    /// have. The memory of destroyed resources is still reused by the heap.
     Native(metal::Heap),
 }
 
@@ -779,6 +876,7 @@ pub(crate) enum MemoryHeap {
 pub struct UnboundBuffer {
     pub(crate) size: u64,
     pub(crate) usage: buffer::Usage,
+    pub(crate) name: String,
 }
 unsafe impl Send for UnboundBuffer {}
 unsafe impl Sync for UnboundBuffer {}
@@ -790,6 +888,7 @@ pub struct UnboundImage {
     pub(crate) kind: image::Kind,
     pub(crate) mip_sizes: Vec<u64>,
     pub(crate) host_visible: bool,
+    pub(crate) name: String,
 }
 unsafe impl Send for UnboundImage {}
 unsafe impl Sync for UnboundImage {}
@@ -805,8 +904,10 @@ pub enum FenceInner {
     Pending(metal::CommandBuffer),
 }
 
+/// A fence with the current state, optionally backed by an `MTLSharedEvent`
+/// that gets signalled on submission.
 #[derive(Debug)]
-pub struct Fence(pub(crate) RefCell<FenceInner>);
+pub struct Fence(pub(crate) RefCell<FenceInner>, pub(crate) Option<Event>);
 
 unsafe impl Send for Fence {}
 unsafe impl Sync for Fence {}
@@ -826,11 +927,23 @@ extern "C" {
         value: c_long,
     ) -> *mut c_void;
 
+    fn dispatch_retain(
+        object: *mut c_void,
+    );
+
     fn dispatch_release(
         object: *mut c_void,
     );
+
+    fn dispatch_time(
+        when: u64,
+        delta: i64,
+    ) -> u64;
 }
 
+const DISPATCH_TIME_NOW: u64 = 0;
+const DISPATCH_TIME_FOREVER: u64 = !0;
+
 #[derive(Clone, Debug)]
 pub struct SystemSemaphore(*mut c_void);
 unsafe impl Send for SystemSemaphore {}
@@ -860,3 +973,91 @@ impl SystemSemaphore {
         }
     }
 }
+
+/// GPU-side `MTLEvent`, used for ordering the submissions on the GPU
+/// without waiting on the CPU.
+///
+/// Shared events (`MTLSharedEvent`) can also be observed from the CPU.
+#[derive(Debug)]
+pub struct Event {
+    raw: *mut Object,
+    value: AtomicUsize,
+    /// `MTLSharedEventListener`, only present for shared events.
+    listener: Option<*mut Object>,
+}
+unsafe impl Send for Event {}
+unsafe impl Sync for Event {}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        unsafe {
+            msg_send![self.raw, release];
+            if let Some(listener) = self.listener {
+                msg_send![listener, release];
+            }
+        }
+    }
+}
+impl Event {
+    pub(crate) fn new(device: &metal::DeviceRef) -> Self {
+        Event {
+            raw: unsafe { msg_send![device, newEvent] },
+            value: AtomicUsize::new(0),
+            listener: None,
+        }
+    }
+    pub(crate) fn new_shared(device: &metal::DeviceRef) -> Self {
+        Event {
+            raw: unsafe { msg_send![device, newSharedEvent] },
+            value: AtomicUsize::new(0),
+            listener: Some(unsafe { msg_send![class!(MTLSharedEventListener), new] }),
+        }
+    }
+    /// Returns true if the last signalled value has been reached on the GPU.
+    /// Only valid for shared events.
+    pub(crate) fn is_signaled(&self) -> bool {
+        let value: u64 = unsafe { msg_send![self.raw, signaledValue] };
+        value >= self.value.load(Ordering::Acquire) as u64
+    }
+    /// Block until the last signalled value is reached on the GPU, or the
+    /// timeout expires. Only valid for shared events.
+    pub(crate) fn wait_signaled(&self, timeout_ns: u64) -> bool {
+        if self.is_signaled() {
+            return true
+        }
+        let listener = self.listener.expect("Only shared events can be waited upon");
+        let value = self.value.load(Ordering::Acquire) as u64;
+        unsafe {
+            let semaphore = dispatch_semaphore_create(0);
+            // the block may outlive this call if we time out
+            dispatch_retain(semaphore);
+            let block = ConcreteBlock::new(move |_event: *mut Object, _value: u64| {
+                dispatch_semaphore_signal(semaphore);
+                dispatch_release(semaphore);
+            }).copy();
+            msg_send![self.raw, notifyListener: listener atValue: value block: block.deref() as *const _];
+            let timeout = if timeout_ns == !0 {
+                DISPATCH_TIME_FOREVER
+            } else {
+                dispatch_time(DISPATCH_TIME_NOW, timeout_ns as i64)
+            };
+            let result = dispatch_semaphore_wait(semaphore, timeout);
+            dispatch_release(semaphore);
+            result == 0
+        }
+    }
+    /// Encode the signal of a new event value into a command buffer.
+    pub(crate) fn signal(&self, cmd_buffer: &metal::CommandBufferRef) {
+        let value = self.value.fetch_add(1, Ordering::AcqRel) + 1;
+        unsafe {
+            msg_send![cmd_buffer, encodeSignalEvent: self.raw value: value as u64];
+        }
+    }
+    /// Encode the wait for the last signalled event value into a command buffer.
+    pub(crate) fn wait(&self, cmd_buffer: &metal::CommandBufferRef) {
+        let value = self.value.load(Ordering::Acquire);
+        unsafe {
+            msg_send![cmd_buffer, encodeWaitForEvent: self.raw value: value as u64];
+        }
+    }
+}
@@ -15,6 +15,8 @@ pub trait Resources {
     type BufferArray;
     type TextureArray;
     type SamplerArray;
+    type ViewportArray;
+    type ScissorArray;
     type DepthStencil;
     type RenderPipeline;
     type ComputePipeline;
@@ -26,6 +28,8 @@ pub struct Own {
     pub buffer_offsets: Vec<hal::buffer::Offset>,
     pub textures: Vec<Option<TexturePtr>>,
     pub samplers: Vec<Option<SamplerPtr>>,
+    pub viewports: Vec<metal::MTLViewport>,
+    pub scissors: Vec<metal::MTLScissorRect>,
 }
 
 impl Resources for Own {
@@ -33,6 +37,8 @@ impl Resources for Own {
     type BufferArray = Range<CacheResourceIndex>;
     type TextureArray = Range<CacheResourceIndex>;
     type SamplerArray = Range<CacheResourceIndex>;
+    type ViewportArray = Range<CacheResourceIndex>;
+    type ScissorArray = Range<CacheResourceIndex>;
     type DepthStencil = metal::DepthStencilState;
     type RenderPipeline = metal::RenderPipelineState;
     type ComputePipeline = metal::ComputePipelineState;
@@ -45,6 +51,8 @@ impl<'a> Resources for &'a Ref {
     type BufferArray = (&'a [Option<BufferPtr>], &'a [hal::buffer::Offset]);
     type TextureArray = &'a [Option<TexturePtr>];
     type SamplerArray = &'a [Option<SamplerPtr>];
+    type ViewportArray = &'a [metal::MTLViewport];
+    type ScissorArray = &'a [metal::MTLScissorRect];
     type DepthStencil = &'a metal::DepthStencilStateRef;
     type RenderPipeline = &'a metal::RenderPipelineStateRef;
     type ComputePipeline = &'a metal::ComputePipelineStateRef;
@@ -54,6 +62,8 @@ impl<'a> Resources for &'a Ref {
 pub enum RenderCommand<R: Resources> {
     SetViewport(hal::pso::Rect, Range<f32>),
     SetScissor(metal::MTLScissorRect),
+    SetViewports(R::ViewportArray),
+    SetScissors(R::ScissorArray),
     SetBlendColor(hal::pso::ColorValue),
     SetDepthBias(hal::pso::DepthBias),
     SetDepthStencilState(R::DepthStencil),
@@ -192,6 +202,8 @@ impl Own {
         self.buffer_offsets.clear();
         self.textures.clear();
         self.samplers.clear();
+        self.viewports.clear();
+        self.scissors.clear();
     }
 
     pub fn own_render(&mut self, com: RenderCommand<&Ref>) -> RenderCommand<Self> {
@@ -199,6 +211,16 @@ impl Own {
         match com {
             SetViewport(rect, depth) => SetViewport(rect, depth),
             SetScissor(rect) => SetScissor(rect),
+            SetViewports(viewports) => SetViewports({
+                let start = self.viewports.len() as CacheResourceIndex;
+                self.viewports.extend_from_slice(viewports);
+                start .. self.viewports.len() as CacheResourceIndex
+            }),
+            SetScissors(scissors) => SetScissors({
+                let start = self.scissors.len() as CacheResourceIndex;
+                self.scissors.extend_from_slice(scissors);
+                start .. self.scissors.len() as CacheResourceIndex
+            }),
             SetBlendColor(color) => SetBlendColor(color),
             SetDepthBias(bias) => SetDepthBias(bias),
             SetDepthStencilState(state) => SetDepthStencilState(state.to_owned()),
@@ -333,6 +355,18 @@ impl<'b, T> AsSlice<Option<T>, &'b Ref> for &'b [Option<T>] {
         self
     }
 }
+impl<'b> AsSlice<metal::MTLViewport, &'b Ref> for &'b [metal::MTLViewport] {
+    #[inline(always)]
+    fn as_slice<'a>(&'a self, _: &'a &'b Ref) -> &'a [metal::MTLViewport] {
+        self
+    }
+}
+impl<'b> AsSlice<metal::MTLScissorRect, &'b Ref> for &'b [metal::MTLScissorRect] {
+    #[inline(always)]
+    fn as_slice<'a>(&'a self, _: &'a &'b Ref) -> &'a [metal::MTLScissorRect] {
+        self
+    }
+}
 impl<'b> AsSlice<Option<BufferPtr>, &'b Ref> for (&'b [Option<BufferPtr>], &'b [hal::buffer::Offset]) {
     #[inline(always)]
     fn as_slice<'a>(&'a self, _: &'a &'b Ref) -> &'a [Option<BufferPtr>] {
@@ -369,6 +403,18 @@ impl AsSlice<Option<SamplerPtr>, Own> for Range<CacheResourceIndex> {
         &resources.samplers[self.start as usize .. self.end as usize]
     }
 }
+impl AsSlice<metal::MTLViewport, Own> for Range<CacheResourceIndex> {
+    #[inline(always)]
+    fn as_slice<'a>(&'a self, resources: &'a Own) -> &'a [metal::MTLViewport] {
+        &resources.viewports[self.start as usize .. self.end as usize]
+    }
+}
+impl AsSlice<metal::MTLScissorRect, Own> for Range<CacheResourceIndex> {
+    #[inline(always)]
+    fn as_slice<'a>(&'a self, resources: &'a Own) -> &'a [metal::MTLScissorRect] {
+        &resources.scissors[self.start as usize .. self.end as usize]
+    }
+}
 
 
 fn _test_render_command_size(com: RenderCommand<Own>) -> [usize; 6] {
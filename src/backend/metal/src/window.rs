@@ -1,4 +1,5 @@
 use {Backend, QueueFamily};
+use conversions as conv;
 use device::{Device, PhysicalDevice};
 use internal::Channel;
 use native;
@@ -133,6 +134,10 @@ struct FrameInner {
 struct Frame {
     inner: Mutex<FrameInner>,
     texture: metal::Texture,
+    /// An intermediate texture exposed to the user instead of the drawable one,
+    /// if the requested format is different from the native layer format.
+    /// Gets blitted onto the drawable at presentation time.
+    proxy: Option<metal::Texture>,
 }
 
 unsafe impl Send for Frame {}
@@ -192,6 +197,15 @@ impl Swapchain {
         }
     }
 
+    /// Returns the pair of (proxy, drawable) textures for the specified swapchain
+    /// image index, if the contents need to be blitted before presentation.
+    pub(crate) fn proxy_blit(&self, index: hal::SwapImageIndex) -> Option<(&metal::TextureRef, &metal::TextureRef)> {
+        let frame = &self.frames[index as usize];
+        frame.proxy
+            .as_ref()
+            .map(|proxy| (proxy.as_ref(), frame.texture.as_ref()))
+    }
+
     fn signal_sync(&self, sync: hal::FrameSync<Backend>) {
         match sync {
             hal::FrameSync::Semaphore(semaphore) => {
@@ -276,6 +290,8 @@ impl hal::Surface<Backend> for Surface {
         let formats = vec![
             format::Format::Bgra8Unorm,
             format::Format::Bgra8Srgb,
+            format::Format::Rgba8Unorm,
+            format::Format::Rgba8Srgb,
             format::Format::Rgba16Float,
         ];
 
@@ -297,6 +313,16 @@ impl hal::Surface<Backend> for Surface {
     }
 }
 
+/// Returns the native layer format to be used for presenting the contents
+/// of the specified RGBA-ordered swapchain format.
+fn layer_format(format: format::Format) -> Option<format::Format> {
+    match format {
+        format::Format::Rgba8Unorm => Some(format::Format::Bgra8Unorm),
+        format::Format::Rgba8Srgb => Some(format::Format::Bgra8Srgb),
+        _ => None,
+    }
+}
+
 impl Device {
     pub(crate) fn build_swapchain(
         &self,
@@ -310,11 +336,34 @@ impl Device {
         let mtl_format = caps
             .map_format(config.format)
             .expect("unsupported backbuffer format");
+        // CAMetalLayer only supports BGRA ordering for 8-bit formats, so RGBA
+        // swapchains are rendered into proxy textures and blitted at present time.
+        let proxy_layer_format = layer_format(config.format);
+        let mtl_layer_format = match proxy_layer_format {
+            Some(format) => caps
+                .map_format(format)
+                .expect("unsupported layer format"),
+            None => mtl_format,
+        };
+        let proxy_descriptor = proxy_layer_format.map(|_| {
+            let descriptor = metal::TextureDescriptor::new();
+            descriptor.set_texture_type(metal::MTLTextureType::D2);
+            descriptor.set_pixel_format(mtl_format);
+            descriptor.set_width(config.extent.width as u64);
+            descriptor.set_height(config.extent.height as u64);
+            descriptor.set_storage_mode(metal::MTLStorageMode::Private);
+            descriptor.set_usage(
+                conv::map_texture_usage(config.image_usage, image::Tiling::Optimal) |
+                metal::MTLTextureUsage::ShaderRead
+            );
+            descriptor
+        });
 
         let render_layer_borrow = surface.inner.render_layer.lock();
         let render_layer = *render_layer_borrow;
         let format_desc = config.format.surface_desc();
-        let framebuffer_only = config.image_usage == image::Usage::COLOR_ATTACHMENT;
+        let framebuffer_only = proxy_descriptor.is_some() ||
+            config.image_usage == image::Usage::COLOR_ATTACHMENT;
         let display_sync = config.present_mode != hal::PresentMode::Immediate;
         let is_mac = caps.os_is_mac;
         let can_set_next_drawable_timeout = if is_mac {
@@ -329,7 +378,7 @@ impl Device {
         unsafe {
             let device_raw = self.shared.device.lock().as_ptr();
             msg_send![render_layer, setDevice: device_raw];
-            msg_send![render_layer, setPixelFormat: mtl_format];
+            msg_send![render_layer, setPixelFormat: mtl_layer_format];
             msg_send![render_layer, setFramebufferOnly: framebuffer_only];
             msg_send![render_layer, setMaximumDrawableCount: config.image_count as u64];
             msg_send![render_layer, setDrawableSize: CGSize::new(config.extent.width as f64, config.extent.height as f64)];
@@ -356,10 +405,11 @@ impl Device {
                     match old_swapchain {
                         Some(ref old) => {
                             let cmd_buffer = cmd_queue.spawn_temp();
+                            let old_frame = &old.frames[0];
                             self.shared.service_pipes.simple_blit(
                                 &self.shared.device,
                                 cmd_buffer,
-                                &old.frames[0].texture,
+                                old_frame.proxy.as_ref().unwrap_or(&old_frame.texture),
                                 texture,
                             );
                             cmd_buffer.present_drawable(drawable);
@@ -384,6 +434,11 @@ impl Device {
                         last_frame: 0,
                     }),
                     texture: texture.to_owned(),
+                    proxy: proxy_descriptor.as_ref().map(|descriptor| {
+                        self.shared.device
+                            .lock()
+                            .new_texture(descriptor)
+                    }),
                 }
             }))
             .collect::<Vec<_>>();
@@ -391,7 +446,9 @@ impl Device {
         let images = frames
             .iter()
             .map(|frame| native::Image {
-                like: native::ImageLike::Texture(frame.texture.clone()),
+                like: native::ImageLike::Texture(
+                    frame.proxy.as_ref().unwrap_or(&frame.texture).clone()
+                ),
                 kind: image::Kind::D2(config.extent.width, config.extent.height, 1, 1),
                 format_desc,
                 shader_channel: Channel::Float,
@@ -53,6 +53,16 @@ pub enum ViewCreationError {
         /// Unsupported format passed on view creation.
         format: Option<format::Format>,
     },
+    /// Buffer view format has no color texel layout, e.g. a depth or stencil format.
+    #[fail(display = "Buffer view format is not a color format ({:?}).", format)]
+    NonColorFormat {
+        /// Format with non-color aspects passed on view creation.
+        format: format::Format,
+    },
+    /// The view can't be created with the given parameters,
+    /// e.g. the offset doesn't meet the alignment required by the backend.
+    #[fail(display = "Buffer view parameters unsupported.")]
+    Unsupported,
 }
 
 bitflags!(
@@ -355,6 +355,11 @@ pub trait Device<B: Backend>: Any + Send + Sync {
         usage: buffer::Usage,
     ) -> Result<B::UnboundBuffer, buffer::CreationError>;
 
+    /// Set the debug name of an unbound buffer, kept when memory is bound.
+    ///
+    /// Backends without debug label support ignore the name.
+    fn set_buffer_name(&self, _buf: &mut B::UnboundBuffer, _name: &str) {}
+
     /// Get memory requirements for the unbound buffer
     fn get_buffer_requirements(&self, buf: &B::UnboundBuffer) -> Requirements;
 
@@ -398,6 +403,11 @@ pub trait Device<B: Backend>: Any + Send + Sync {
         view_caps: image::ViewCapabilities,
     ) -> Result<B::UnboundImage, image::CreationError>;
 
+    /// Set the debug name of an unbound image, kept when memory is bound.
+    ///
+    /// Backends without debug label support ignore the name.
+    fn set_image_name(&self, _image: &mut B::UnboundImage, _name: &str) {}
+
     /// Get memory requirements for the unbound Image
     fn get_image_requirements(&self, image: &B::UnboundImage) -> Requirements;
 
@@ -14,6 +14,8 @@ pub struct ComputePipelineDesc<'a, B: Backend> {
     pub flags: PipelineCreationFlags,
     /// The parent pipeline to this one, if any.
     pub parent: BasePipeline<'a, B::ComputePipeline>,
+    /// Optional debug name of the pipeline.
+    pub name: Option<&'a str>,
 }
 
 impl<'a, B: Backend> ComputePipelineDesc<'a, B> {
@@ -27,6 +29,7 @@ impl<'a, B: Backend> ComputePipelineDesc<'a, B> {
             layout,
             flags: PipelineCreationFlags::empty(),
             parent: BasePipeline::None,
+            name: None,
         }
     }
 }
@@ -126,6 +126,8 @@ pub struct GraphicsPipelineDesc<'a, B: Backend> {
     /// The parent pipeline, which may be
     /// `BasePipeline::None`.
     pub parent: BasePipeline<'a, B::GraphicsPipeline>,
+    /// Optional debug name of the pipeline.
+    pub name: Option<&'a str>,
 }
 
 impl<'a, B: Backend> GraphicsPipelineDesc<'a, B> {
@@ -151,6 +153,7 @@ impl<'a, B: Backend> GraphicsPipelineDesc<'a, B> {
             subpass,
             flags: PipelineCreationFlags::empty(),
             parent: BasePipeline::None,
+            name: None,
         }
     }
 }
@@ -678,6 +678,7 @@ impl<B: hal::Backend> Scene<B, hal::General> {
                             },
                             flags: pso::PipelineCreationFlags::empty(),
                             parent: pso::BasePipeline::None,
+                            name: Some(name.as_str()),
                         };
                         let pso = device.create_graphics_pipelines(&[desc], None)
                             .swap_remove(0)
@@ -698,6 +699,7 @@ impl<B: hal::Backend> Scene<B, hal::General> {
                                 .expect(&format!("Missing pipeline layout: {}", layout)),
                             flags: pso::PipelineCreationFlags::empty(),
                             parent: pso::BasePipeline::None,
+                            name: Some(name.as_str()),
                         };
                         let pso = device.create_compute_pipelines(&[desc], None)
                             .swap_remove(0)